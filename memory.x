@@ -1,19 +1,152 @@
-MEMORY
-{
-  FLASH  : ORIGIN = 0x80200000, LENGTH = 1M     /* Kernel .text */
-  RODATA : ORIGIN = 0x80300000, LENGTH = 1M     /* Kernel .rodata */
-  RAM    : ORIGIN = 0x80400000, LENGTH = 2M     /* Kernel .data, .bss, heap */
-  HEAP   : ORIGIN = 0x80700000, LENGTH = 2M
-}
+/* Kernel linker script.
+ *
+ * The kernel is loaded at KERNEL_PHYS_BASE but linked at KERNEL_VIRT_BASE in the upper half of
+ * the Sv39 address space. The small `.boot` section is linked at its physical address, it enables
+ * paging with a static boot page table and then jumps to `_start` in the upper half.
+ */
 
-REGION_ALIAS("REGION_TEXT",   FLASH);
-REGION_ALIAS("REGION_RODATA", RODATA);
-REGION_ALIAS("REGION_DATA",   RAM);
-REGION_ALIAS("REGION_BSS",    RAM);
-REGION_ALIAS("REGION_HEAP",   HEAP);
-REGION_ALIAS("REGION_STACK",  RAM);            /* Stack also in RAM */
+OUTPUT_ARCH(riscv)
+ENTRY(_boot)
 
-_stack_start = ORIGIN(RAM) + LENGTH(RAM);      /* Top of RAM for stack */
+KERNEL_PHYS_BASE = 0x80200000;
+KERNEL_VIRT_OFFSET = 0xffffffff00000000;                 /* Keep in sync with src/page.rs */
+KERNEL_VIRT_BASE = KERNEL_PHYS_BASE + KERNEL_VIRT_OFFSET;
+
+/* _max_hart_id and _hart_stack_size are defined in src/stack.rs, every hart stack slot starts
+   with an unmapped guard page */
 _heap_size = 1M;
-_hart_stack_size = 1K;
-_max_hart_id = 1;
+_stack_size = (_max_hart_id + 1) * _hart_stack_size;
+
+/* riscv-rt entry points and handlers, these are normally provided by its link.x. `_start_trap`
+   is defined in src/trap.rs, so the handlers below are never called by riscv-rt */
+EXTERN(_default_abort);
+PROVIDE(abort = _default_abort);
+PROVIDE(_pre_init_trap = _default_abort);
+PROVIDE(_default_mp_hook = abort);
+PROVIDE(_mp_hook = _default_mp_hook);
+EXTERN(_default_setup_interrupts);
+PROVIDE(_setup_interrupts = _default_setup_interrupts);
+PROVIDE(ExceptionHandler = abort);
+PROVIDE(DefaultHandler = abort);
+PROVIDE(_start_DefaultHandler_trap = _start_trap);
+
+PROVIDE(InstructionMisaligned = ExceptionHandler);
+PROVIDE(InstructionFault = ExceptionHandler);
+PROVIDE(IllegalInstruction = ExceptionHandler);
+PROVIDE(Breakpoint = ExceptionHandler);
+PROVIDE(LoadMisaligned = ExceptionHandler);
+PROVIDE(LoadFault = ExceptionHandler);
+PROVIDE(StoreMisaligned = ExceptionHandler);
+PROVIDE(StoreFault = ExceptionHandler);
+PROVIDE(UserEnvCall = ExceptionHandler);
+PROVIDE(SupervisorEnvCall = ExceptionHandler);
+PROVIDE(MachineEnvCall = ExceptionHandler);
+PROVIDE(InstructionPageFault = ExceptionHandler);
+PROVIDE(LoadPageFault = ExceptionHandler);
+PROVIDE(StorePageFault = ExceptionHandler);
+
+PROVIDE(SupervisorSoft = DefaultHandler);
+PROVIDE(MachineSoft = DefaultHandler);
+PROVIDE(SupervisorTimer = DefaultHandler);
+PROVIDE(MachineTimer = DefaultHandler);
+PROVIDE(SupervisorExternal = DefaultHandler);
+PROVIDE(MachineExternal = DefaultHandler);
+
+SECTIONS
+{
+  . = KERNEL_PHYS_BASE;
+
+  /* Runs with paging disabled, so it is linked at its physical address */
+  .boot :
+  {
+    KEEP(*(.boot.text));
+    . = ALIGN(4K);
+    KEEP(*(.boot.data));
+    . = ALIGN(4K);
+  }
+
+  . += KERNEL_VIRT_OFFSET;
+  _kernel_start = KERNEL_VIRT_BASE;                      /* First byte of the kernel image */
+
+  .text : AT(ADDR(.text) - KERNEL_VIRT_OFFSET)
+  {
+    __stext = .;
+
+    KEEP(*(.init));
+    . = ALIGN(4);
+    *(.trap);
+    *(.trap.rust);
+    *(.text.abort);
+    *(.text .text.*);
+
+    . = ALIGN(4);
+    __etext = .;
+  }
+
+  .rodata : AT(ADDR(.rodata) - KERNEL_VIRT_OFFSET) ALIGN(4K)
+  {
+    __srodata = .;
+
+    *(.srodata .srodata.*);
+    *(.rodata .rodata.*);
+
+    . = ALIGN(8);
+    __erodata = .;
+  }
+
+  .data : AT(ADDR(.data) - KERNEL_VIRT_OFFSET) ALIGN(4K)
+  {
+    __sdata = .;
+
+    /* Must be called __global_pointer$ for linker relaxations to work. */
+    PROVIDE(__global_pointer$ = . + 0x800);
+    *(.sdata .sdata.* .sdata2 .sdata2.*);
+    *(.data .data.*);
+
+    . = ALIGN(8);
+    __edata = .;
+  }
+
+  /* .data is loaded in place, so the copy done by riscv-rt is a no-op */
+  __sidata = __sdata;
+
+  .bss (NOLOAD) : AT(ADDR(.bss) - KERNEL_VIRT_OFFSET) ALIGN(4K)
+  {
+    __sbss = .;
+
+    *(.sbss .sbss.* .bss .bss.*);
+
+    . = ALIGN(4K);
+    __ebss = .;
+  }
+
+  .stack (NOLOAD) : AT(ADDR(.stack) - KERNEL_VIRT_OFFSET) ALIGN(_hart_stack_size)
+  {
+    __estack = .;
+    . += _stack_size;
+    _stack_start = .;
+    __sstack = .;
+  }
+
+  .heap (NOLOAD) : AT(ADDR(.heap) - KERNEL_VIRT_OFFSET) ALIGN(4K)
+  {
+    __sheap = .;
+    . += _heap_size;
+    __eheap = .;
+  }
+
+  . = ALIGN(4K);
+  _kernel_end = .;                                       /* First byte after the kernel image */
+
+  /* Dynamic relocations are unsupported, this section only detects relocatable code */
+  .got (INFO) :
+  {
+    KEEP(*(.got .got.*));
+  }
+}
+
+ASSERT(_start_trap % 4 == 0, "_start_trap is not 4-byte aligned");
+ASSERT(_pre_init_trap % 4 == 0, "_pre_init_trap is not 4-byte aligned");
+ASSERT(SIZEOF(.stack) >= (_max_hart_id + 1) * _hart_stack_size,
+       ".stack is too small for the stacks of all harts");
+ASSERT(SIZEOF(.got) == 0, ".got section detected, dynamic relocations are not supported");
@@ -1,39 +1,68 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::page::page_table::LeafTable;
+use crate::page::{FrameAllocator, HEAP_GROW_SIZE, HEAP_GROW_START};
 use crate::println;
-use memory_addr::{
-    PAGE_SIZE_4K, PageIter, PhysAddr, VirtAddr, align_down_4k, align_up_4k,
-};
-use page_table_multiarch::riscv::Sv39PageTable;
+#[cfg(feature = "heap-debug")]
+use crate::stack;
+use conquer_once::spin::OnceCell;
+use memory_addr::{PAGE_SIZE_4K, VirtAddr};
 use page_table_multiarch::{MappingFlags, PageSize, PagingResult};
-use crate::allocator::buddy::BuddyAllocator;
-use crate::page::FrameAllocator;
-
-pub mod buddy;
-pub mod fixed_size_block;
+use wiheom_lib::allocator::Locked;
+#[cfg(feature = "heap-debug")]
+use wiheom_lib::allocator::debug::AllocSite;
+use wiheom_lib::allocator::heap::{Heap, HeapStats};
+use wiheom_lib::allocator::slab;
+use wiheom_lib::page::PageFlags;
 
 unsafe extern "C" {
     static __sheap: u8;
     static __eheap: u8;
 }
 
-/// A wrapper around spin::Mutex to permit trait implementation.
-pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+/// Returns null once neither the heap nor the frame allocator have memory left, the default
+/// alloc error handler then panics with the size of the failed allocation.
+#[global_allocator]
+static ALLOCATOR: Locked<Heap> = Locked::new(new_heap());
+
+/// The table the heap maps its 2M pages into once paging is set up.
+static HEAP_TABLE: OnceCell<LeafTable> = OnceCell::uninit();
+
+/// End of the memory the heap has grown into so far.
+static HEAP_END: AtomicUsize = AtomicUsize::new(HEAP_GROW_START);
+
+const fn new_heap() -> Heap {
+    let heap = Heap::with_grow(grow_heap);
+    #[cfg(feature = "heap-debug")]
+    let heap = heap.with_site(alloc_site);
+    heap
 }
 
-impl<A> Locked<A> {
-    pub const fn new(inner: A) -> Self {
-        Locked {
-            inner: spin::Mutex::new(inner),
-        }
-    }
+/// A cache of kernel objects of type `T` whose slabs come from the kernel heap.
+///
+/// ```ignore
+/// static THREADS: KmemCache<Thread> = KmemCache::new("thread", allocator::heap());
+/// ```
+#[allow(dead_code)]
+pub type KmemCache<T> = slab::KmemCache<T, Locked<Heap>>;
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
-        self.inner.lock()
-    }
+/// The kernel heap, the page source of [`KmemCache`]s.
+#[allow(dead_code)]
+pub const fn heap() -> &'static Locked<Heap> {
+    &ALLOCATOR
 }
 
-#[global_allocator]
-static ALLOCATOR: Locked<BuddyAllocator> = Locked::new(BuddyAllocator::new());
+/// A snapshot of the counters of the kernel heap.
+pub fn stats() -> HeapStats {
+    ALLOCATOR.lock().stats()
+}
+
+/// Prints the usage of the kernel heap.
+pub fn print_stats() {
+    // Not holding the heap lock while printing
+    let stats = stats();
+    println!("{}", stats);
+}
 
 // pub const STACK_START: usize = RAM_START + RAM_SIZE;
 // pub const STACK_SIZE: usize = 1024;
@@ -52,3 +81,82 @@ pub fn init_heap() -> PagingResult {
 
     Ok(())
 }
+
+/// Lets the heap grow into the pages of `table`, which has to cover the range at
+/// [`HEAP_GROW_START`].
+pub fn enable_heap_growth(table: LeafTable) {
+    HEAP_TABLE.init_once(|| table);
+}
+
+/// Maps at least `min_size` bytes of new memory behind the heap, in 2M pages.
+///
+/// The heap is grown by at least its grown size so far, to keep the number of buddy regions low.
+/// Called with the heap locked, so it may not allocate.
+fn grow_heap(min_size: usize) -> Option<(usize, usize)> {
+    let step = PageSize::Size2M as usize;
+    let table = HEAP_TABLE.get()?;
+    let start = HEAP_END.load(Ordering::Relaxed);
+    let size = min_size
+        .max(start - HEAP_GROW_START)
+        .next_multiple_of(step)
+        .min(HEAP_GROW_START + HEAP_GROW_SIZE - start);
+
+    let mut grown = 0;
+    while grown < size {
+        let frames = step / PAGE_SIZE_4K;
+        let Some(frame) = FrameAllocator::alloc_contiguous(frames, frames, PageFlags::SLAB) else {
+            break;
+        };
+        let vaddr = VirtAddr::from(start + grown);
+        match unsafe { table.map(vaddr, frame, MappingFlags::READ | MappingFlags::WRITE) } {
+            Ok(flush) => flush.flush(),
+            Err(e) => {
+                println!("Failed to map kernel heap at {:#x}: {:?}", vaddr, e);
+                FrameAllocator::dealloc_contiguous(frame, frames);
+                break;
+            }
+        }
+        grown += step;
+    }
+
+    if grown == 0 {
+        println!("Kernel heap can not grow by {:#x} bytes", min_size);
+        return None;
+    }
+    HEAP_END.store(start + grown, Ordering::Relaxed);
+    println!("Grew kernel heap by {} KiB at {:#x}", grown / 1024, start);
+    Some((start, grown))
+}
+
+/// The return addresses of the frames of an allocation, found by following the frame pointers.
+///
+/// The first frames are those of the allocator itself. Without frame pointers, which the
+/// `build-debug-heap` alias turns on, `s0` is an ordinary register and the walk stops at the first
+/// value that does not point into the stack.
+#[cfg(feature = "heap-debug")]
+fn alloc_site() -> AllocSite {
+    let mut site = AllocSite::default();
+    let (bottom, top) = stack::hart_stack(stack::current_hart());
+    let mut fp: usize;
+    unsafe { core::arch::asm!("mv {}, s0", out(reg) fp) };
+    for ra in &mut site.0 {
+        if !(bottom + 2 * size_of::<usize>()..=top).contains(&fp)
+            || !fp.is_multiple_of(size_of::<usize>())
+        {
+            break;
+        }
+        // The return address and the caller's frame pointer are saved right below `fp`
+        let frame = fp as *const usize;
+        let next;
+        unsafe {
+            *ra = frame.sub(1).read();
+            next = frame.sub(2).read();
+        }
+        // Frames of callers lie higher up on the same stack
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+    site
+}
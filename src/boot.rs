@@ -0,0 +1,69 @@
+use core::arch::global_asm;
+
+use crate::page::{KERNEL_PHYS_BASE, KERNEL_VIRT_OFFSET, LINEAR_MAP_SIZE, PHYS_VIRT_OFFSET};
+
+/// Size of a Sv39 gigapage.
+const GIGAPAGE: usize = 1 << 30;
+
+/// Valid, readable, writable, executable, accessed and dirty.
+const BOOT_FLAGS: usize = 0xcf;
+
+// Entry point of the kernel, the firmware jumps here with paging disabled.
+//
+// The kernel is linked in the upper half, so before any Rust code runs paging is enabled with a
+// static page table built from gigapages:
+// - an identity mapping of the gigabyte the kernel is loaded into, so the trampoline keeps
+//   running after satp is written
+// - the linear map of physical memory at `PHYS_VIRT_OFFSET`
+// - the kernel image at `KERNEL_VIRT_OFFSET`
+//
+// a0 (hart id) and a1 (device tree) are preserved for `_start`. sscratch is cleared, which tells
+// the trap entry that traps come from the kernel.
+global_asm!(
+    ".section .boot.text, \"ax\"
+    .global _boot
+    .option push
+    .option norelax
+_boot:
+    csrw sscratch, zero
+    la t0, boot_page_table
+    srli t0, t0, 12
+    li t1, 8 << 60 // Sv39
+    or t0, t0, t1
+    sfence.vma
+    csrw satp, t0
+    sfence.vma
+
+    // Jump to the upper half, the address has to be loaded as _start is out of reach of auipc
+1:
+    auipc t0, %pcrel_hi(2f)
+    ld t0, %pcrel_lo(1b)(t0)
+    jr t0
+    .align 3
+2:
+    .dword _start
+    .option pop
+
+    .section .boot.data, \"aw\"
+    .align 12
+boot_page_table:
+    .org boot_page_table + {identity} * 8
+    .quad ({identity} << 28) | {flags}
+
+    .org boot_page_table + {linear} * 8
+    .set gigapage, 0
+    .rept {linear_count}
+    .quad (gigapage << 28) | {flags}
+    .set gigapage, gigapage + 1
+    .endr
+
+    .org boot_page_table + {kernel} * 8
+    .quad ({identity} << 28) | {flags}
+
+    .org boot_page_table + 4096",
+    identity = const KERNEL_PHYS_BASE / GIGAPAGE,
+    linear = const (PHYS_VIRT_OFFSET / GIGAPAGE) % 512,
+    linear_count = const LINEAR_MAP_SIZE / GIGAPAGE,
+    kernel = const ((KERNEL_PHYS_BASE + KERNEL_VIRT_OFFSET) / GIGAPAGE) % 512,
+    flags = const BOOT_FLAGS,
+);
@@ -1,6 +1,15 @@
-use crate::println;
+use crate::page::page_table::PagingMode;
+use crate::{page, println};
+use conquer_once::spin::OnceCell;
 use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use fdt::Fdt;
+use fdt::node::NodeProperty;
+use wiheom_lib::device_tree;
+use wiheom_lib::numa::{Distances, MAX_NODES};
+
+static FDT: OnceCell<Fdt<'static>> = OnceCell::uninit();
+static DTB_ADDR: AtomicUsize = AtomicUsize::new(0);
 
 pub fn dtb_ptr() -> usize {
     let dtb_ptr: usize;
@@ -12,41 +21,18 @@ pub fn dtb_ptr() -> usize {
 
 /// Reads the 'reg' property based on the address and size cells
 fn read_reg_from_cells(reg: &[u8], address_cells: u32, size_cells: u32) -> Option<(usize, usize)> {
-    if reg.len() < (address_cells + size_cells) as usize {
-        println!(
-            "Error: 'reg' property is too short for address_cells={} and size_cells={}",
-            address_cells, size_cells
-        );
-        return None;
-    }
-    let address = match address_cells {
-        1 => u32::from_be_bytes(reg[0..4].try_into().unwrap()) as usize,
-        2 => u64::from_be_bytes(reg[0..8].try_into().unwrap()) as usize,
-        _ => {
-            println!("Error: Unsupported address_cells value: {}", address_cells);
-            return None;
-        }
-    };
-    let size =
-        match size_cells {
-            1 => u32::from_be_bytes(reg[address_cells as usize * 4..][0..4].try_into().unwrap())
-                as usize,
-            2 => u64::from_be_bytes(reg[address_cells as usize * 4..][0..8].try_into().unwrap())
-                as usize,
-            _ => {
-                println!("Error: Unsupported size_cells value: {}", size_cells);
-                return None;
-            }
-        };
-    Some((address, size))
+    device_tree::read_reg_from_cells(reg, address_cells, size_cells)
+        .inspect_err(|e| {
+            println!("Error: {}", e);
+        })
+        .ok()
 }
 
 pub fn init(dtb_ptr: usize) {
     println!("Initializing device tree...");
     println!("Device Tree Pointer: {:#x}", dtb_ptr);
 
-    let dtb = unsafe { core::slice::from_raw_parts(dtb_ptr as *const u8, 0x10000) };
-    let fdt = match Fdt::new(dtb) {
+    let fdt = match unsafe { Fdt::from_ptr(page::phys_to_virt(dtb_ptr) as *const u8) } {
         Ok(fdt) => fdt,
         Err(e) => {
             println!("Failed to parse device tree: {:?}", e);
@@ -119,5 +105,224 @@ pub fn init(dtb_ptr: usize) {
         println!("No SoC node found in device tree");
     }
 
+    DTB_ADDR.store(dtb_ptr, Ordering::Relaxed);
+    FDT.init_once(|| fdt);
+
     println!("Device Tree Initialization complete");
 }
+
+/// Returns the parsed device tree.
+///
+/// Panics if called before [`init`].
+pub fn fdt() -> &'static Fdt<'static> {
+    FDT.get().expect("device tree is not initialized")
+}
+
+/// Physical address range `(start, end)` of the device tree blob.
+pub fn dtb_range() -> (usize, usize) {
+    let start = DTB_ADDR.load(Ordering::Relaxed);
+    (start, start + fdt().total_size())
+}
+
+/// A range of RAM that must not be handed out, from `/memreserve/` or `/reserved-memory`.
+#[derive(Debug, Clone, Copy)]
+pub struct Reservation {
+    pub name: &'static str,
+    pub start: usize,
+    pub size: usize,
+    /// The range must not be mapped by the kernel at all.
+    pub no_map: bool,
+}
+
+/// A `/reserved-memory` child without a `reg` property that the kernel has to place itself.
+#[derive(Debug, Clone, Copy)]
+pub struct DynamicReservation {
+    pub name: &'static str,
+    pub size: usize,
+    pub alignment: usize,
+    pub no_map: bool,
+    alloc_ranges: Option<&'static [u8]>,
+    address_cells: u32,
+    size_cells: u32,
+}
+
+impl DynamicReservation {
+    /// Ranges `(start, size)` the reservation has to be placed in, if it is restricted.
+    pub fn alloc_ranges(&self) -> Option<impl Iterator<Item = (usize, usize)>> {
+        let stride = (self.address_cells + self.size_cells) as usize * 4;
+        let (address_cells, size_cells) = (self.address_cells, self.size_cells);
+        self.alloc_ranges.map(move |ranges| {
+            ranges
+                .chunks_exact(stride)
+                .filter_map(move |range| read_reg_from_cells(range, address_cells, size_cells))
+        })
+    }
+}
+
+/// Reads a `#address-cells` or `#size-cells` style property
+fn cells(value: &[u8]) -> Option<u32> {
+    value.try_into().ok().map(u32::from_be_bytes)
+}
+
+/// Static reservations from the `/memreserve/` block of the header and from the `reg` property
+/// of every `/reserved-memory` child.
+pub fn reservations() -> impl Iterator<Item = Reservation> {
+    let header = fdt().memory_reservations().map(|reservation| Reservation {
+        name: "/memreserve/",
+        start: reservation.address() as usize,
+        size: reservation.size(),
+        no_map: false,
+    });
+
+    let nodes = fdt()
+        .find_node("/reserved-memory")
+        .into_iter()
+        .flat_map(|reserved| reserved.children())
+        .filter_map(|child| {
+            let no_map = child.property("no-map").is_some();
+            let regions = child.reg()?;
+            Some(regions.filter_map(move |region| {
+                Some(Reservation {
+                    name: child.name,
+                    start: region.starting_address as usize,
+                    size: region.size?,
+                    no_map,
+                })
+            }))
+        })
+        .flatten();
+
+    header.chain(nodes)
+}
+
+/// `/reserved-memory` children that only specify a `size` and leave the placement to the kernel.
+pub fn dynamic_reservations() -> impl Iterator<Item = DynamicReservation> {
+    fdt()
+        .find_node("/reserved-memory")
+        .into_iter()
+        .flat_map(|reserved| {
+            let address_cells = reserved
+                .property("#address-cells")
+                .and_then(|p| cells(p.value))
+                .unwrap_or(2);
+            let size_cells = reserved
+                .property("#size-cells")
+                .and_then(|p| cells(p.value))
+                .unwrap_or(1);
+            reserved.children().filter_map(move |child| {
+                if child.property("reg").is_some() {
+                    return None;
+                }
+                Some(DynamicReservation {
+                    name: child.name,
+                    size: child.property("size")?.as_usize()?,
+                    alignment: child
+                        .property("alignment")
+                        .and_then(|p| p.as_usize())
+                        .unwrap_or(1),
+                    no_map: child.property("no-map").is_some(),
+                    alloc_ranges: child.property("alloc-ranges").map(|p| p.value),
+                    address_cells,
+                    size_cells,
+                })
+            })
+        })
+}
+
+/// Physical memory regions `(start, size, node)` from every node with `device_type = "memory"`,
+/// together with their NUMA node.
+pub fn memory_regions() -> impl Iterator<Item = (usize, usize, usize)> {
+    fdt()
+        .all_nodes()
+        .filter(|node| node.property("device_type").and_then(|p| p.as_str()) == Some("memory"))
+        .filter_map(|node| {
+            let numa_node = numa_node(node.property("numa-node-id"));
+            let regions = node.reg()?;
+            Some(regions.filter_map(move |region| {
+                Some((region.starting_address as usize, region.size?, numa_node))
+            }))
+        })
+        .flatten()
+}
+
+/// Reads a `numa-node-id` property, nodes without one are on node 0.
+///
+/// Nodes the kernel does not keep apart are folded into the last one.
+fn numa_node(property: Option<NodeProperty>) -> usize {
+    property
+        .and_then(|p| p.as_usize())
+        .unwrap_or(0)
+        .min(MAX_NODES - 1)
+}
+
+/// The NUMA node of the cpu with the hart id `hart`.
+pub fn hart_node(hart: usize) -> usize {
+    numa_node(
+        fdt()
+            .cpus()
+            .find(|cpu| cpu.ids().first() == hart)
+            .and_then(|cpu| cpu.property("numa-node-id")),
+    )
+}
+
+/// The distances between the NUMA nodes of the memory and cpu nodes, from the `distance-map`
+/// node if there is one.
+pub fn numa_distances() -> Distances {
+    let cpu_nodes = fdt()
+        .cpus()
+        .map(|cpu| numa_node(cpu.property("numa-node-id")));
+    let nodes = memory_regions()
+        .map(|(_, _, node)| node)
+        .chain(cpu_nodes)
+        .max()
+        .map_or(1, |node| node + 1);
+    let mut distances = Distances::new(nodes);
+    let matrix = fdt()
+        .find_compatible(&["numa-distance-map-v1"])
+        .and_then(|map| map.property("distance-matrix"));
+    if let Some(matrix) = matrix {
+        // Entries of three cells, from, to and distance
+        for entry in matrix.value.chunks_exact(12) {
+            let [from, to, distance] =
+                [0, 4, 8].map(|at| u32::from_be_bytes(entry[at..at + 4].try_into().unwrap()));
+            distances.set(from as usize, to as usize, distance);
+        }
+    }
+    distances
+}
+
+/// The widest paging mode every cpu supports according to its `mmu-type` property.
+pub fn paging_mode() -> Option<PagingMode> {
+    fdt()
+        .cpus()
+        .map(|cpu| {
+            cpu.property("mmu-type")
+                .and_then(|p| p.as_str())
+                .and_then(PagingMode::from_mmu_type)
+        })
+        .min()
+        .flatten()
+}
+
+/// Whether every cpu lists `extension` in its `riscv,isa-extensions` property.
+pub fn has_isa_extension(extension: &str) -> bool {
+    let mut cpus = fdt().cpus().peekable();
+    cpus.peek().is_some()
+        && cpus.all(|cpu| {
+            cpu.property("riscv,isa-extensions")
+                .is_some_and(|p| device_tree::string_list(p.value).any(|name| name == extension))
+        })
+}
+
+/// The smallest `riscv,cbom-block-size` of all cpus, the block size of the Zicbom cache
+/// operations.
+pub fn cbom_block_size() -> Option<usize> {
+    fdt()
+        .cpus()
+        .map(|cpu| {
+            cpu.property("riscv,cbom-block-size")
+                .and_then(|p| p.as_usize())
+        })
+        .min()
+        .flatten()
+}
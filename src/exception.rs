@@ -1,78 +1,79 @@
 use riscv::interrupt::Exception;
 
-use crate::println;
+use crate::page::{self, vma::Access};
+use crate::trap::{A0, TrapFrame};
+use crate::{println, stack};
 
-#[riscv_rt::exception(Exception::InstructionMisaligned)]
-fn instruction_misaligned_handler(trap_frame: &riscv_rt::TrapFrame) -> ! {
-    println!("Instruction Misaligned: {:?}", trap_frame);
-    loop {}
-}
-
-#[riscv_rt::exception(Exception::InstructionFault)]
-fn instruction_fault_handler(trap_frame: &riscv_rt::TrapFrame) -> ! {
-    println!("Instruction Fault: {:?}", trap_frame);
-    loop {}
-}
-
-#[riscv_rt::exception(Exception::IllegalInstruction)]
-fn illigal_instruction_handler(trap_frame: &riscv_rt::TrapFrame) -> ! {
-    println!("Illigal Instruction: {:?}", trap_frame);
-    loop {}
-}
-
-#[riscv_rt::exception(Exception::Breakpoint)]
-fn breakpoint_handler(trap_frame: &riscv_rt::TrapFrame) {
-    println!("Breakpoint: {:?}", trap_frame);
-}
-
-#[riscv_rt::exception(Exception::LoadMisaligned)]
-fn load_misaligned_handler(trap_frame: &riscv_rt::TrapFrame) -> ! {
-    println!("Load Misaligned: {:?}", trap_frame);
-    loop {}
-}
-
-#[riscv_rt::exception(Exception::LoadFault)]
-fn load_fault_handler(trap_frame: &riscv_rt::TrapFrame) -> ! {
-    println!("Load Fault: {:?}", trap_frame);
-    loop {}
-}
+/// Error returned by system calls the kernel does not implement.
+const ENOSYS: isize = 38;
 
-#[riscv_rt::exception(Exception::StoreMisaligned)]
-fn store_misaligned_handler(trap_frame: &riscv_rt::TrapFrame) -> ! {
-    println!("Store Misaligned: {:?}", trap_frame);
-    loop {}
+/// Handles an exception, the interrupted code resumes with `trap_frame` afterwards.
+pub fn handle_exception(exception: Exception, trap_frame: &mut TrapFrame) {
+    match exception {
+        Exception::InstructionMisaligned => fatal("Instruction Misaligned", trap_frame),
+        Exception::InstructionFault => fatal("Instruction Fault", trap_frame),
+        Exception::IllegalInstruction => fatal("Illigal Instruction", trap_frame),
+        Exception::Breakpoint => breakpoint_handler(trap_frame),
+        Exception::LoadMisaligned => fatal("Load Misaligned", trap_frame),
+        Exception::LoadFault => fatal("Load Fault", trap_frame),
+        Exception::StoreMisaligned => fatal("Store Misaligned", trap_frame),
+        Exception::StoreFault => fatal("Store Fault", trap_frame),
+        Exception::UserEnvCall => user_env_call_handler(trap_frame),
+        Exception::SupervisorEnvCall => supervisor_env_call_handler(trap_frame),
+        Exception::InstructionPageFault => page_fault_handler(Access::Execute, trap_frame),
+        Exception::LoadPageFault => page_fault_handler(Access::Read, trap_frame),
+        Exception::StorePageFault => page_fault_handler(Access::Write, trap_frame),
+    }
 }
 
-#[riscv_rt::exception(Exception::StoreFault)]
-fn store_fault_handler(trap_frame: &riscv_rt::TrapFrame) -> ! {
-    println!("Store Fault: {:?}", trap_frame);
-    loop {}
+/// Reports an exception the kernel can't recover from.
+fn fatal(kind: &str, trap_frame: &TrapFrame) -> ! {
+    let mode = if trap_frame.is_user() {
+        "user"
+    } else {
+        "kernel"
+    };
+    panic!("{} in {} mode\n{}", kind, mode, trap_frame);
 }
 
-#[riscv_rt::exception(Exception::UserEnvCall)]
-fn user_env_call_handler(trap_frame: &riscv_rt::TrapFrame) {
-    println!("User Env Call: {:?}", trap_frame);
+/// Reports a page fault in the guard page of a kernel stack as a stack overflow.
+fn check_stack_overflow(trap_frame: &TrapFrame) {
+    if let Some(hart) = stack::guard_page_hart(trap_frame.stval) {
+        panic!("kernel stack overflow on hart {}\n{}", hart, trap_frame);
+    }
 }
 
-#[riscv_rt::exception(Exception::SupervisorEnvCall)]
-fn supervisor_env_call_handler(trap_frame: &riscv_rt::TrapFrame) {
-    println!("Supervisor Env Call: {:?}", trap_frame);
+fn breakpoint_handler(trap_frame: &mut TrapFrame) {
+    println!("Breakpoint at {:#x}", trap_frame.sepc);
+    trap_frame.skip_instruction();
 }
 
-#[riscv_rt::exception(Exception::InstructionPageFault)]
-fn instruction_page_fault_handler(trap_frame: &riscv_rt::TrapFrame) -> ! {
-    println!("Instruction Page Fault: {:?}", trap_frame);
-    loop {}
+fn user_env_call_handler(trap_frame: &mut TrapFrame) {
+    println!("User Env Call: {}", trap_frame);
+    trap_frame.regs[A0] = -ENOSYS as usize;
+    trap_frame.sepc += 4;
 }
 
-#[riscv_rt::exception(Exception::LoadPageFault)]
-fn load_page_fault_handler(trap_frame: &riscv_rt::TrapFrame) -> ! {
-    println!("Load Page Fault: {:?}", trap_frame);
-    loop {}
+fn supervisor_env_call_handler(trap_frame: &mut TrapFrame) {
+    println!("Supervisor Env Call: {}", trap_frame);
+    trap_frame.sepc += 4;
 }
 
-#[riscv_rt::exception(Exception::StorePageFault)]
-fn store_page_fault_handler(trap_frame: &riscv_rt::TrapFrame) -> ! {
-    println!("Store Page Fault: {:?}", trap_frame);
-    loop {}
+/// Resolves a page fault through the address space, or reports it with the translation path of
+/// the faulting address.
+fn page_fault_handler(access: Access, trap_frame: &TrapFrame) {
+    check_stack_overflow(trap_frame);
+    let vaddr = trap_frame.stval;
+    if page::handle_page_fault(vaddr, access, trap_frame.is_user()) {
+        return;
+    }
+    if let Some(translation) = page::translate(vaddr) {
+        println!("{}", translation);
+    }
+    let kind = match access {
+        Access::Execute => "Instruction",
+        Access::Read => "Load",
+        Access::Write => "Store",
+    };
+    panic!("{} Page Fault at {:#x}\n{}", kind, vaddr, trap_frame);
 }
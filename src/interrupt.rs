@@ -34,7 +34,14 @@ pub fn interrupt_init() {
     }
 }
 
-#[riscv_rt::core_interrupt(Interrupt::SupervisorTimer)]
+/// Handles an interrupt, the interrupted code resumes afterwards.
+pub fn handle_interrupt(interrupt: Interrupt) {
+    match interrupt {
+        Interrupt::SupervisorTimer => supervisor_timer_handler(),
+        _ => interrupt_handler(interrupt),
+    }
+}
+
 fn supervisor_timer_handler() {
     println!("Supervisor Timer Interrupt");
 
@@ -53,7 +60,6 @@ fn supervisor_timer_handler() {
     }
 }
 
-#[unsafe(export_name = "DefaultHandler")]
-unsafe fn interrupt_handler(interrupt: Interrupt) {
+fn interrupt_handler(interrupt: Interrupt) {
     println!("Interrupt: {:?}", interrupt);
 }
@@ -2,7 +2,6 @@
 #![no_std]
 
 use core::panic::PanicInfo;
-use alloc::boxed::Box;
 use riscv::register::satp::Satp;
 extern crate alloc;
 
@@ -10,8 +9,12 @@ pub mod serial;
 mod exception;
 mod interrupt;
 mod page;
+mod stack;
 mod allocator;
+mod boot;
 mod device_tree;
+mod sbi;
+mod trap;
 
 #[riscv_rt::entry]
 fn main() -> ! {
@@ -24,8 +27,9 @@ fn main() -> ! {
     device_tree::init(dtb);
 
     unsafe { page::init_frame_allocator() };
-    let _page_table = unsafe { page::init_page_table() };
+    // The kernel address space keeps its areas on the heap
     allocator::init_heap().unwrap();
+    unsafe { page::init_page_table() };
     interrupt::interrupt_init();
 
     println!("Initializing done");
@@ -33,11 +37,29 @@ fn main() -> ! {
     let reg = Satp::from_bits(0);
     println!("{:?}", reg.mode());
 
+    println!("Press 'm' for a memory report, 'p' for the page table, 'q' to shut down");
     loop {
         riscv::asm::wfi();
+        match serial::try_receive() {
+            Some(b'm') => {
+                allocator::print_stats();
+                page::print_frame_stats();
+            }
+            Some(b'p') => page::dump_kernel_space(),
+            Some(b'q') => shutdown(),
+            _ => {}
+        }
     }
 }
 
+/// Prints the final reports and powers the machine off.
+fn shutdown() -> ! {
+    println!("Shutting down");
+    allocator::print_stats();
+    page::print_frame_stats();
+    sbi::shutdown()
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
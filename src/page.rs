@@ -1,11 +1,29 @@
-use memory_addr::{PAGE_SIZE_4K, PageIter, VirtAddr};
+use alloc::sync::Arc;
+use conquer_once::spin::OnceCell;
+use memory_addr::{PAGE_SIZE_4K, VirtAddr};
 use memory_addr::{PhysAddr, align_down_4k, align_up_4k};
-use page_table_multiarch::{MappingFlags, PageSize, PagingError};
-use page_table_multiarch::{PagingHandler, riscv::Sv39PageTable};
-use riscv::register::satp::{self, Mode, Satp};
+use page_table_multiarch::PagingHandler;
+use page_table_multiarch::{MappingFlags, PageSize, PagingResult};
+use riscv::register::satp;
 use spin::Mutex;
 
-use crate::println;
+use crate::serial::SERIAL_PORT_BASE_ADDRESS;
+use crate::{allocator, device_tree, println, stack};
+
+pub mod address_space;
+pub mod dma;
+pub mod page_table;
+pub mod tlb;
+pub mod vma;
+pub mod vmalloc;
+
+use address_space::AddressSpace;
+use page_table::{PageTable, PagingMode, Translation};
+use vma::{Access, Backing};
+use wiheom_lib::frame::BitmapAllocator;
+use wiheom_lib::memory_map::{MemoryMap, RegionKind};
+use wiheom_lib::numa::{Distances, MAX_NODES};
+use wiheom_lib::page::{FrameList, Page, PageArray, PageFlags};
 
 unsafe extern "C" {
     static __stext: u8;
@@ -20,76 +38,113 @@ unsafe extern "C" {
     static __sbss: u8;
     static __ebss: u8;
 
-    static _stack_start: u8;
+    static __sheap: u8;
+    static __eheap: u8;
+
+    static _kernel_start: u8;
+    static _kernel_end: u8;
+}
+
+/// Physical address the kernel image is loaded at, keep in sync with `memory.x`.
+pub const KERNEL_PHYS_BASE: usize = 0x8020_0000;
+
+/// Offset between the virtual and the physical addresses of the kernel image.
+pub const KERNEL_VIRT_OFFSET: usize = 0xffff_ffff_0000_0000;
+
+/// Start of the linear map of all physical memory, the first address of the upper half.
+pub const PHYS_VIRT_OFFSET: usize = 0xffff_ffc0_0000_0000;
+
+/// Amount of physical memory covered by the linear map of the boot page table.
+pub const LINEAR_MAP_SIZE: usize = 128 << 30;
+
+/// Start of the virtual range the kernel heap grows into, right behind the linear map.
+pub const HEAP_GROW_START: usize = PHYS_VIRT_OFFSET + LINEAR_MAP_SIZE;
+
+/// Size of the range the kernel heap grows into, the range of a single table of 2M pages.
+pub const HEAP_GROW_SIZE: usize = 1 << 30;
+
+/// Address of `paddr` in the linear map.
+pub const fn phys_to_virt(paddr: usize) -> usize {
+    paddr + PHYS_VIRT_OFFSET
+}
+
+/// Physical address of `vaddr` in the linear map.
+#[allow(dead_code)]
+pub const fn virt_to_phys(vaddr: usize) -> usize {
+    vaddr - PHYS_VIRT_OFFSET
+}
+
+/// Physical address of `vaddr` in the kernel image.
+pub const fn kernel_virt_to_phys(vaddr: usize) -> usize {
+    vaddr - KERNEL_VIRT_OFFSET
 }
 
 pub fn addr(sym: *const u8) -> usize {
     sym as usize
 }
 
+/// Maps the kernel image from `start` to `end` to its physical address.
 pub fn map_section(
-    page_table: &mut Sv39PageTable<FrameAllocator>,
+    space: &mut AddressSpace,
     start: *const u8,
     end: *const u8,
     flags: MappingFlags,
-) -> Result<(), PagingError> {
-    let start = align_down_4k(addr(start));
-    let end = align_up_4k(addr(end));
-    println!(
-        "Mapped section from {:#x} to {:#x} with flags {:?}",
-        start, end, flags
-    );
-    let _ = page_table.map_region(
-        VirtAddr::from(start),
-        |vaddr| PhysAddr::from(vaddr.as_usize()),
-        end - start,
-        flags,
-        false,
-        true,
-    )?;
-    Ok(())
-}
-
-pub fn map_section_size(
-    page_table: &mut Sv39PageTable<FrameAllocator>,
-    start: *const u8,
-    size: usize,
-    flags: MappingFlags,
-) -> Result<(), PagingError> {
-    let start = align_down_4k(addr(start));
-    let end = align_up_4k(start + size);
+) -> PagingResult {
+    let (start, end) = (addr(start), addr(end));
     println!(
         "Mapped section from {:#x} to {:#x} with flags {:?}",
-        start, end, flags
+        align_down_4k(start),
+        align_up_4k(end),
+        flags
     );
-    let _ = page_table.map_region(
-        VirtAddr::from(start),
-        |vaddr| PhysAddr::from(vaddr.as_usize()),
+    space.map(
+        start,
         end - start,
         flags,
-        false,
-        true,
-    )?;
-    Ok(())
+        Backing::Physical(kernel_virt_to_phys(start)),
+    )
 }
 
-pub unsafe fn map_kernel_sections(
-    page_table: &mut Sv39PageTable<FrameAllocator>,
-) -> Result<(), PagingError> {
+pub unsafe fn map_kernel_sections(space: &mut AddressSpace) -> PagingResult {
     unsafe {
         map_section(
-            page_table,
+            space,
             &__stext,
             &__etext,
             MappingFlags::READ | MappingFlags::EXECUTE,
         )?;
 
-        map_section(page_table, &__srodata, &__erodata, MappingFlags::READ)?;
+        map_section(space, &__srodata, &__erodata, MappingFlags::READ)?;
 
-        map_section_size(
-            page_table,
+        map_section(
+            space,
             &__sdata,
-            0x400_000, // 4MB
+            &__edata,
+            MappingFlags::READ | MappingFlags::WRITE,
+        )?;
+
+        map_section(
+            space,
+            &__sbss,
+            &__ebss,
+            MappingFlags::READ | MappingFlags::WRITE,
+        )?;
+
+        // The guard page at the bottom of every stack stays unmapped
+        for hart in 0..stack::MAX_HARTS {
+            let (bottom, top) = stack::hart_stack(hart);
+            map_section(
+                space,
+                bottom as *const u8,
+                top as *const u8,
+                MappingFlags::READ | MappingFlags::WRITE,
+            )?;
+        }
+
+        map_section(
+            space,
+            &__sheap,
+            &__eheap,
             MappingFlags::READ | MappingFlags::WRITE,
         )?;
     }
@@ -97,95 +152,623 @@ pub unsafe fn map_kernel_sections(
     Ok(())
 }
 
-pub struct FrameAllocator {
-    start: PhysAddr,
-    end: PhysAddr,
-    next: usize,
+/// Physical frame allocator used by the kernel page tables.
+///
+/// All state lives in the global [`Zone`]s and [`PageArray`], so page tables can allocate and
+/// free their frames through [`PagingHandler`]. Frames are reference counted, freeing one only
+/// drops a reference. Every NUMA node has its own zone, allocations prefer the node of the
+/// current hart and fall back to the other nodes by their distance.
+pub struct FrameAllocator;
+
+/// The frames of RAM on one NUMA node.
+///
+/// The frames freed most recently are handed out first, they stay marked as used in the bitmap
+/// while they are on the free list.
+struct Zone {
+    bitmap: BitmapAllocator,
+    free: FrameList,
+}
+
+impl Zone {
+    fn free_frames(&self) -> usize {
+        self.bitmap.free_frames() + self.free.len()
+    }
+}
+
+/// Where the memory of the NUMA nodes is and how far apart they are.
+struct Numa {
+    distances: Distances,
+    /// The physical range spanned by the memory of every node, empty if it has none.
+    spans: [(usize, usize); MAX_NODES],
+    /// The node of every hart.
+    hart_nodes: [usize; stack::MAX_HARTS],
 }
 
-// Size of the RAM in bytes (2MB)
-pub const RAM_SIZE: usize = 2 * 1024 * 1024;
+/// The address space the kernel runs in, set up by [`init_page_table`].
+static KERNEL_SPACE: OnceCell<Arc<Mutex<AddressSpace>>> = OnceCell::uninit();
+
+static MEMORY_MAP: Mutex<MemoryMap> = Mutex::new(MemoryMap::new());
+
+static ZONES: [Mutex<Zone>; MAX_NODES] = [const {
+    Mutex::new(Zone {
+        bitmap: BitmapAllocator::empty(),
+        free: FrameList::new(),
+    })
+}; MAX_NODES];
 
-static mut FRAME_ALLOCATOR: Mutex<Option<FrameAllocator>> = Mutex::new(None);
+static NUMA: OnceCell<Numa> = OnceCell::uninit();
+
+/// The metadata of every frame between the lowest and highest address of RAM.
+static PAGES: OnceCell<PageArray> = OnceCell::uninit();
 
 impl FrameAllocator {
-    pub unsafe fn init(start: usize, size: usize) {
-        #[allow(static_mut_refs)]
-        let mut allocator = unsafe { FRAME_ALLOCATOR.lock() };
-        *allocator = Some(Self {
-            start: PhysAddr::from_usize(start),
-            end: PhysAddr::from_usize(start + size),
-            next: 0,
+    /// Hands all usable memory in `memory_map` to the zones of the NUMA nodes, whose memory
+    /// spans the ranges in `spans`.
+    ///
+    /// The bitmaps and the page array tracking the frames are carved out of the first usable
+    /// regions that can hold them.
+    ///
+    /// # Safety
+    /// The caller must guarantee that the usable regions are unused RAM and that this is only
+    /// called once.
+    pub unsafe fn init(memory_map: &mut MemoryMap, mut spans: [(usize, usize); MAX_NODES]) {
+        let (start, end) = memory_map.span().expect("no physical memory");
+        for span in &mut spans {
+            if span.0 >= span.1 {
+                *span = (0, 0);
+            }
+        }
+        let overlaps = spans.iter().enumerate().any(|(node, a)| {
+            spans[node + 1..]
+                .iter()
+                .any(|b| a.0 < a.1 && b.0 < b.1 && a.0 < b.1 && b.0 < a.1)
+        });
+        if overlaps {
+            println!("The memory of the NUMA nodes overlaps, treating it as a single node");
+            spans = [(0, 0); MAX_NODES];
+            spans[0] = (start, end);
+        }
+
+        for (zone, &(span_start, span_end)) in ZONES.iter().zip(&spans) {
+            if span_start == span_end {
+                continue;
+            }
+            let frames = (span_end - span_start) / PAGE_SIZE_4K;
+            let bitmap = carve(
+                memory_map,
+                BitmapAllocator::bitmap_size(frames) / size_of::<u64>(),
+                RegionKind::FrameBitmap,
+            );
+            zone.lock().bitmap = BitmapAllocator::new(span_start, frames, bitmap);
+        }
+        let frames = (end - start) / PAGE_SIZE_4K;
+        let pages = PageArray::new(start, carve(memory_map, frames, RegionKind::PageArray));
+
+        for region in memory_map.iter() {
+            match region.kind {
+                RegionKind::Usable => {
+                    // Every zone only takes the part it covers
+                    for zone in &ZONES {
+                        zone.lock().bitmap.add_range(region.start, region.end);
+                    }
+                    pages.free_range(region.start, region.end);
+                }
+                RegionKind::Kernel
+                | RegionKind::DeviceTree
+                | RegionKind::FrameBitmap
+                | RegionKind::PageArray => {
+                    pages.mark_range(region.start, region.end, PageFlags::KERNEL)
+                }
+                RegionKind::Firmware | RegionKind::Reserved | RegionKind::ReservedNoMap => {}
+            }
+        }
+        PAGES.init_once(|| pages);
+        NUMA.init_once(|| Numa {
+            distances: device_tree::numa_distances(),
+            spans,
+            hart_nodes: core::array::from_fn(device_tree::hart_node),
         });
     }
 
-    fn usable_frames(&self) -> PageIter<PAGE_SIZE_4K, PhysAddr> {
-        PageIter::<PAGE_SIZE_4K, PhysAddr>::new(self.start, self.end).unwrap()
+    /// The metadata of the frame at `paddr`, `None` if it is not covered by the allocator.
+    pub fn page(paddr: PhysAddr) -> Option<&'static Page> {
+        PAGES.get()?.page(paddr)
+    }
+
+    /// The NUMA node of the frame at `paddr`.
+    pub fn node(paddr: PhysAddr) -> usize {
+        NUMA.get()
+            .and_then(|numa| {
+                numa.spans
+                    .iter()
+                    .position(|&(start, end)| (start..end).contains(&paddr.as_usize()))
+            })
+            .unwrap_or(0)
+    }
+
+    /// The NUMA node of the current hart, whose memory is preferred for its allocations.
+    pub fn current_node() -> usize {
+        NUMA.get()
+            .map_or(0, |numa| numa.hart_nodes[stack::current_hart()])
+    }
+
+    /// Number of NUMA nodes.
+    pub fn nodes() -> usize {
+        NUMA.get().map_or(1, |numa| numa.distances.nodes())
+    }
+
+    /// Runs `alloc` on the zones in the fallback order of the current node until it succeeds.
+    fn alloc_from(alloc: impl Fn(&mut Zone, &PageArray) -> Option<PhysAddr>) -> Option<PhysAddr> {
+        let numa = NUMA.get()?;
+        let pages = PAGES.get()?;
+        numa.distances
+            .fallback_order(Self::current_node())
+            .find_map(|node| alloc(&mut ZONES[node].lock(), pages))
+    }
+
+    /// Allocates a single frame for `flags`, with one reference.
+    pub fn alloc(flags: PageFlags) -> Option<PhysAddr> {
+        let paddr =
+            Self::alloc_from(|zone, pages| zone.free.pop(pages).or_else(|| zone.bitmap.alloc()))?;
+        Self::page(paddr).unwrap().allocated(flags);
+        Some(paddr)
+    }
+
+    /// Allocates `count` physically contiguous frames for `flags` aligned to `align` frames,
+    /// each with one reference.
+    pub fn alloc_contiguous(count: usize, align: usize, flags: PageFlags) -> Option<PhysAddr> {
+        let paddr = Self::alloc_from(|zone, pages| {
+            // The free list would leave holes in the bitmap
+            while let Some(paddr) = zone.free.pop(pages) {
+                zone.bitmap.dealloc(paddr);
+            }
+            zone.bitmap.alloc_contiguous(count, align)
+        })?;
+        for frame in 0..count {
+            Self::page(paddr + frame * PAGE_SIZE_4K)
+                .unwrap()
+                .allocated(flags);
+        }
+        Some(paddr)
+    }
+
+    /// Frees `count` frames allocated with [`FrameAllocator::alloc_contiguous`].
+    ///
+    /// Panics if any of them has other references.
+    pub fn dealloc_contiguous(paddr: PhysAddr, count: usize) {
+        let pages = PAGES.get().expect("frame allocator is not initialized");
+        for frame in 0..count {
+            let page = pages.page(paddr + frame * PAGE_SIZE_4K).unwrap();
+            assert!(
+                page.put(),
+                "freeing shared frame {:#x}",
+                paddr + frame * PAGE_SIZE_4K
+            );
+        }
+        ZONES[Self::node(paddr)]
+            .lock()
+            .bitmap
+            .dealloc_contiguous(paddr, count)
+    }
+
+    /// Takes another reference to the frame at `paddr`, so it is shared.
+    pub fn get(paddr: PhysAddr) {
+        Self::page(paddr).expect("frame without metadata").get();
+    }
+
+    /// Drops a reference to the frame at `paddr`, and frees it if it was the last one.
+    pub fn put(paddr: PhysAddr) {
+        let pages = PAGES.get().expect("frame allocator is not initialized");
+        if pages.page(paddr).expect("frame without metadata").put() {
+            ZONES[Self::node(paddr)].lock().free.push(pages, paddr);
+        }
+    }
+
+    /// Number of frames of `node` that are free and the number of frames it spans.
+    pub fn node_usage(node: usize) -> (usize, usize) {
+        let zone = ZONES[node].lock();
+        (zone.free_frames(), zone.bitmap.total_frames())
+    }
+
+    /// Number of frames that are currently free.
+    pub fn free_frames() -> usize {
+        (0..MAX_NODES).map(|node| Self::node_usage(node).0).sum()
+    }
+
+    /// Number of frames managed by the allocator.
+    pub fn total_frames() -> usize {
+        (0..MAX_NODES).map(|node| Self::node_usage(node).1).sum()
     }
 }
 
+/// Carves memory for `count` values of `T` out of the usable memory in `memory_map`.
+fn carve<T>(memory_map: &mut MemoryMap, count: usize, kind: RegionKind) -> &'static mut [T] {
+    let size = align_up_4k(count * size_of::<T>());
+    let start = memory_map
+        .allocate(size, PAGE_SIZE_4K, kind)
+        .unwrap_or_else(|| panic!("no memory for the {:?}", kind));
+    unsafe { core::slice::from_raw_parts_mut(phys_to_virt(start) as *mut T, count) }
+}
+
 impl PagingHandler for FrameAllocator {
     fn alloc_frame() -> Option<PhysAddr> {
-        #[allow(static_mut_refs)]
-        let mut allocator = unsafe { FRAME_ALLOCATOR.lock() };
-        let allocator = allocator.as_mut().unwrap();
-        let frame = allocator.usable_frames().nth(allocator.next);
-        allocator.next += 1;
-        frame
+        Self::alloc(PageFlags::KERNEL)
     }
 
-    fn dealloc_frame(_paddr: PhysAddr) {}
+    fn dealloc_frame(paddr: PhysAddr) {
+        Self::put(paddr)
+    }
 
     fn phys_to_virt(paddr: PhysAddr) -> VirtAddr {
-        VirtAddr::from(paddr.as_usize())
+        VirtAddr::from(phys_to_virt(paddr.as_usize()))
+    }
+}
+
+fn reserved_kind(no_map: bool) -> RegionKind {
+    if no_map {
+        RegionKind::ReservedNoMap
+    } else {
+        RegionKind::Reserved
+    }
+}
+
+/// Applies the `/memreserve/` entries and `/reserved-memory` nodes of the device tree.
+fn reserve_device_tree_memory(memory_map: &mut MemoryMap) {
+    for reservation in device_tree::reservations() {
+        let (start, end) = (reservation.start, reservation.start + reservation.size);
+        println!(
+            "Reserved memory {}: {:#x} - {:#x}{}",
+            reservation.name,
+            start,
+            end,
+            if reservation.no_map { " (no-map)" } else { "" }
+        );
+        memory_map.reserve(
+            align_down_4k(start),
+            align_up_4k(end),
+            reserved_kind(reservation.no_map),
+        );
+    }
+
+    for reservation in device_tree::dynamic_reservations() {
+        let kind = reserved_kind(reservation.no_map);
+        let size = align_up_4k(reservation.size);
+        let align = reservation.alignment.max(PAGE_SIZE_4K);
+        let start = match reservation.alloc_ranges() {
+            Some(mut ranges) => ranges.find_map(|(start, len)| {
+                memory_map.allocate_within(size, align, start, start + len, kind)
+            }),
+            None => memory_map.allocate(size, align, kind),
+        };
+        if let Some(start) = start {
+            println!(
+                "Reserved memory {}: {:#x} - {:#x}{}",
+                reservation.name,
+                start,
+                start + size,
+                if reservation.no_map { " (no-map)" } else { "" }
+            );
+        } else {
+            println!(
+                "Failed to place reserved memory {} of {:#x} bytes",
+                reservation.name, reservation.size
+            );
+        }
     }
 }
 
 pub unsafe fn init_frame_allocator() {
     println!("Initializing frame allocator");
-    let ram_start; 
+    let mut memory_map = MEMORY_MAP.lock();
+    let mut spans = [(usize::MAX, 0); MAX_NODES];
+    for (start, size, node) in device_tree::memory_regions() {
+        let (start, end) = (align_up_4k(start), align_down_4k(start + size));
+        memory_map.add_ram(start, end);
+        spans[node] = (spans[node].0.min(start), spans[node].1.max(end));
+    }
+
+    let (kernel_start, kernel_end) = unsafe {
+        (
+            kernel_virt_to_phys(addr(&_kernel_start)),
+            kernel_virt_to_phys(addr(&_kernel_end)),
+        )
+    };
+    // The SBI firmware is loaded at the start of the memory bank the kernel is loaded into and
+    // owns everything below the kernel
+    let bank = memory_map
+        .iter()
+        .find(|region| region.start <= kernel_start && kernel_start < region.end)
+        .copied();
+    if let Some(bank) = bank {
+        memory_map.reserve(bank.start, kernel_start, RegionKind::Firmware);
+    }
+    memory_map.reserve(
+        align_down_4k(kernel_start),
+        align_up_4k(kernel_end),
+        RegionKind::Kernel,
+    );
+    let (dtb_start, dtb_end) = device_tree::dtb_range();
+    memory_map.reserve(
+        align_down_4k(dtb_start),
+        align_up_4k(dtb_end),
+        RegionKind::DeviceTree,
+    );
+
+    reserve_device_tree_memory(&mut memory_map);
+
+    unsafe { FrameAllocator::init(&mut memory_map, spans) };
+    print_numa_nodes();
+
+    println!("Physical memory map:");
+    for region in memory_map.iter() {
+        println!("\t{}", region);
+    }
+    println!(
+        "Finished initializing frame allocator: {} of {} frames free, {} MiB of RAM",
+        FrameAllocator::free_frames(),
+        FrameAllocator::total_frames(),
+        memory_map.iter().map(|region| region.size()).sum::<usize>() / (1024 * 1024)
+    );
+}
+
+/// Panics if any page mapped by `page_table` is both writable and executable.
+fn audit_wx(page_table: &PageTable) {
+    let mut violation = None;
+    page_table.walk(|_level, vaddr, entry| {
+        let flags = entry.flags();
+        if entry.is_leaf() && flags.contains(MappingFlags::WRITE | MappingFlags::EXECUTE) {
+            violation.get_or_insert((vaddr, flags));
+        }
+    });
+    if let Some((vaddr, flags)) = violation {
+        panic!(
+            "W^X violation: page {:#x} is mapped with flags {:?}",
+            vaddr, flags
+        );
+    }
+    println!("W^X audit passed");
+}
+
+/// Panics if a write to a page shared by `fork` reaches the other address space, or if the
+/// kernel is not mapped in a forked address space.
+///
+/// The area starts out read-only and is only made writable in the child, so the page has to be
+/// copy-on-write even though it was not writable when it was shared.
+fn check_copy_on_write() {
+    const START: usize = 0x1000_0000;
+    let page = VirtAddr::from(START);
+    let frame = |space: &AddressSpace| space.page_table().query(page).unwrap().0;
+
+    // A fork of the kernel address space is empty apart from the shared upper half
+    let mut parent = kernel_space().lock().fork().unwrap();
+    parent
+        .map(
+            START,
+            PAGE_SIZE_4K,
+            MappingFlags::READ | MappingFlags::USER,
+            Backing::Anonymous,
+        )
+        .unwrap();
+    parent.populate(START, PAGE_SIZE_4K).unwrap();
+    let shared = frame(&parent);
+    let bytes = FrameAllocator::phys_to_virt(shared).as_mut_ptr();
+    unsafe { bytes.write_bytes(0xa5, PAGE_SIZE_4K) };
+
+    let mut child = parent.fork().unwrap();
+    let text = VirtAddr::from(unsafe { addr(&__stext) });
+    assert!(
+        child.page_table().query(text).is_ok(),
+        "the kernel is not mapped in a forked address space"
+    );
+    child
+        .protect(
+            START,
+            PAGE_SIZE_4K,
+            MappingFlags::READ | MappingFlags::WRITE | MappingFlags::USER,
+        )
+        .unwrap();
+    assert!(child.handle_page_fault(START, Access::Write, true));
+    let copy = frame(&child);
+    assert_ne!(copy, shared, "the write did not copy the shared page");
     unsafe {
-        ram_start = addr(&_stack_start);
+        FrameAllocator::phys_to_virt(copy)
+            .as_mut_ptr()
+            .write_bytes(0x5a, PAGE_SIZE_4K)
     };
-    unsafe { FrameAllocator::init(ram_start, RAM_SIZE) };
-    println!("Finished initializing frame allocator");
+
+    assert_eq!(frame(&parent), shared);
+    let parent_bytes = unsafe { core::slice::from_raw_parts(bytes, PAGE_SIZE_4K) };
+    assert!(
+        parent_bytes.iter().all(|&byte| byte == 0xa5),
+        "a write in the child changed the page of the parent"
+    );
+    println!("Copy-on-write self-test passed");
 }
 
-pub unsafe fn init_page_table() -> Sv39PageTable<FrameAllocator> {
-    println!("Initializing page table");
-    let mut page_table = Sv39PageTable::<FrameAllocator>::try_new().unwrap();
+/// Maps all RAM outside of the kernel image that the kernel may access into the linear map.
+///
+/// Reserved memory stays reachable, unless the device tree marks it `no-map`. Firmware and
+/// `no-map` memory are left out, so a stray access faults instead of touching memory the
+/// kernel does not own.
+fn map_physical_memory(space: &mut AddressSpace) -> PagingResult {
+    let memory_map = MEMORY_MAP.lock();
+    for region in memory_map.iter() {
+        match region.kind {
+            RegionKind::Usable
+            | RegionKind::DeviceTree
+            | RegionKind::FrameBitmap
+            | RegionKind::PageArray
+            | RegionKind::Reserved => {
+                println!(
+                    "Mapped physical memory from {:#x} to {:#x}",
+                    region.start, region.end
+                );
+                space.map(
+                    phys_to_virt(region.start),
+                    region.size(),
+                    MappingFlags::READ | MappingFlags::WRITE,
+                    Backing::Physical(region.start),
+                )?;
+            }
+            RegionKind::Firmware | RegionKind::Kernel | RegionKind::ReservedNoMap => {}
+        }
+    }
+    Ok(())
+}
+
+/// Builds the kernel address space for `mode`.
+unsafe fn build_kernel_space(mode: PagingMode) -> AddressSpace {
+    let mut space = AddressSpace::new(mode).unwrap();
 
     unsafe {
-        if let Err(e) = map_kernel_sections(&mut page_table) {
+        if let Err(e) = map_kernel_sections(&mut space) {
             panic!("Failed to map kernel sections: {:?}", e);
         }
     }
 
+    if let Err(e) = map_physical_memory(&mut space) {
+        panic!("Failed to map physical memory: {:?}", e);
+    }
+
     // Map serial port for uart
-    let _ = page_table
+    space
         .map(
-            VirtAddr::from_usize(0x1000_0000),
-            PhysAddr::from_usize(0x1000_0000),
-            PageSize::Size4K,
-            MappingFlags::READ | MappingFlags::WRITE,
+            phys_to_virt(SERIAL_PORT_BASE_ADDRESS),
+            PAGE_SIZE_4K,
+            MappingFlags::READ | MappingFlags::WRITE | MappingFlags::DEVICE,
+            Backing::Physical(SERIAL_PORT_BASE_ADDRESS),
         )
         .unwrap();
 
-    let mut reg = Satp::from_bits(0);
-    reg.set_mode(Mode::Sv39);
-    let ppn = page_table.root_paddr().as_usize() >> 12;
-    reg.set_ppn(ppn);
-    println!("Turn on MMU");
-    println!(
-        "Page table root PhysAddr: {:#x}, aligned: {}",
-        page_table.root_paddr().as_usize(),
-        page_table.root_paddr().as_usize() % PAGE_SIZE_4K
-    );
-    println!("Satp bits: {:#x}", reg.bits());
-    println!("Satp PPN: {:#x}", reg.ppn());
+    audit_wx(space.page_table());
+    space
+}
+
+/// Builds the kernel address space with the widest paging mode the harts support and switches
+/// to it.
+///
+/// The mode comes from the `mmu-type` of the cpu nodes. A satp write with an unsupported mode
+/// has no effect, so the write is checked and the next narrower mode is tried if it did not
+/// stick.
+pub unsafe fn init_page_table() {
+    println!("Initializing page table");
+    unsafe { tlb::init() };
+    dma::init();
+    let mut mode = device_tree::paging_mode().unwrap_or(PagingMode::Sv39);
+    let space = loop {
+        println!("Trying paging mode {:?}", mode);
+        let space = Arc::new(Mutex::new(unsafe { build_kernel_space(mode) }));
+        let root = space.lock().page_table().root_paddr().as_usize();
+        unsafe { AddressSpace::switch(&space) };
+        println!(
+            "Page table root PhysAddr: {:#x}, ASID: {:?}",
+            root,
+            space.lock().asid()
+        );
+        if satp::read().ppn() == root >> 12 {
+            break space;
+        }
+        // Still running on the boot page table
+        mode = mode
+            .narrower()
+            .expect("the hart does not support Sv39 paging");
+        println!("Paging mode is not supported, falling back to {:?}", mode);
+    };
+    let heap_table = space
+        .lock()
+        .reserve(
+            HEAP_GROW_START,
+            HEAP_GROW_SIZE,
+            PageSize::Size2M,
+            MappingFlags::READ | MappingFlags::WRITE,
+        )
+        .expect("failed to reserve the kernel heap range");
+    allocator::enable_heap_growth(heap_table);
+    println!("Satp bits: {:#x}", satp::read().bits());
     println!("stvec: {:#x}", riscv::register::stvec::read().bits());
-    riscv::asm::sfence_vma_all();
-    unsafe { satp::write(reg) };
+    KERNEL_SPACE.init_once(|| space);
+    check_copy_on_write();
     println!("Finished initializing page table");
-    page_table
+}
+
+/// The address space of the kernel.
+///
+/// Panics if called before [`init_page_table`].
+pub fn kernel_space() -> &'static Mutex<AddressSpace> {
+    KERNEL_SPACE
+        .get()
+        .expect("kernel address space is not initialized")
+}
+
+/// Resolves a page fault at `vaddr` in the address space the hart runs in.
+///
+/// Kernel faults in the upper half of a forked address space may also be caused by a root
+/// entry the kernel address space created after the fork, which is copied over then.
+///
+/// Returns false if the access is a real violation, or if the fault was raised while the
+/// address space was locked, as waiting for the lock would never return. `user` tells if the
+/// fault was raised in user mode.
+pub fn handle_page_fault(vaddr: usize, access: Access, user: bool) -> bool {
+    address_space::with_current(|space| {
+        if space.handle_page_fault(vaddr, access, user) {
+            return true;
+        }
+        // The kernel address space itself is locked already, it never shares its upper half
+        !user
+            && KERNEL_SPACE
+                .get()
+                .and_then(|kernel| kernel.try_lock())
+                .is_some_and(|kernel| space.sync_kernel_half(&kernel, vaddr))
+    })
+    .unwrap_or(false)
+}
+
+/// Walks the active page table for `vaddr`, without taking any locks so faults can use it.
+///
+/// Returns `None` if paging is off.
+pub fn translate(vaddr: usize) -> Option<Translation> {
+    let satp = satp::read();
+    let mode = PagingMode::from_satp_mode(satp.mode())?;
+    let root = PhysAddr::from(satp.ppn() << 12);
+    Some(unsafe { Translation::walk(root, mode, VirtAddr::from(vaddr)) })
+}
+
+/// Prints every mapping of the kernel address space.
+pub fn dump_kernel_space() {
+    println!("{}", kernel_space().lock().page_table().dump());
+}
+
+/// Prints how the frames of RAM are used, from their metadata.
+pub fn print_frame_stats() {
+    let (free, reserved, used) = PAGES.get().map_or((0, 0, 0), PageArray::counts);
+    println!(
+        "Frames: {} free, {} in use, {} reserved",
+        free, used, reserved
+    );
+    for node in 0..FrameAllocator::nodes() {
+        let (free, total) = FrameAllocator::node_usage(node);
+        println!("\tNode {}: {} of {} frames free", node, free, total);
+    }
+}
+
+/// Prints the memory of every NUMA node and its distances to the other nodes.
+fn print_numa_nodes() {
+    let Some(numa) = NUMA.get() else {
+        return;
+    };
+    for node in 0..numa.distances.nodes() {
+        let (start, end) = numa.spans[node];
+        println!(
+            "NUMA node {}: {:#x} - {:#x}, distances {:?}",
+            node,
+            start,
+            end,
+            numa.distances.row(node)
+        );
+    }
+    println!(
+        "Boot hart {} is on node {}",
+        stack::current_hart(),
+        FrameAllocator::current_node()
+    );
 }
@@ -0,0 +1,442 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use memory_addr::{PAGE_SIZE_4K, PhysAddr, VirtAddr, align_down_4k, align_up_4k};
+use page_table_multiarch::{MappingFlags, PageSize, PagingError, PagingHandler, PagingResult};
+use riscv::register::satp::{self, Satp};
+use spin::Mutex;
+use wiheom_lib::asid::Asid;
+use wiheom_lib::page::PageFlags;
+
+use super::page_table::{self, LeafTable, PageTable, PagingMode};
+use super::vma::{Access, Backing, Vma, VmaList};
+use super::{FrameAllocator, tlb};
+use crate::println;
+use crate::stack::{self, MAX_HARTS};
+
+/// The address space every hart runs in, recorded by [`AddressSpace::switch`].
+static CURRENT: [Mutex<Option<Arc<Mutex<AddressSpace>>>>; MAX_HARTS] =
+    [const { Mutex::new(None) }; MAX_HARTS];
+
+/// Calls `func` with the address space the current hart runs in.
+///
+/// Returns `None` without waiting if the address space is locked, like when a fault is raised
+/// while it is changed, or if the hart has not switched to an address space yet.
+pub fn with_current<R>(func: impl FnOnce(&mut AddressSpace) -> R) -> Option<R> {
+    let current = CURRENT[stack::current_hart()].try_lock()?;
+    let mut space = current.as_ref()?.try_lock()?;
+    Some(func(&mut space))
+}
+
+/// A page table together with the areas that are mapped in it.
+///
+/// Every mapping is made through an area, so the page fault handler can tell lazily mapped
+/// pages from real access violations.
+pub struct AddressSpace {
+    page_table: PageTable,
+    areas: VmaList,
+    /// The ASID the address space last ran with, it gets one when it is first switched to.
+    asid: Option<Asid>,
+}
+
+impl AddressSpace {
+    /// Creates an empty address space using `mode`.
+    pub fn new(mode: PagingMode) -> PagingResult<Self> {
+        Ok(Self {
+            page_table: PageTable::try_new(mode)?,
+            areas: VmaList::new(),
+            asid: None,
+        })
+    }
+
+    pub fn page_table(&self) -> &PageTable {
+        &self.page_table
+    }
+
+    pub fn asid(&self) -> Option<u16> {
+        self.asid.map(Asid::value)
+    }
+
+    pub fn areas(&self) -> &VmaList {
+        &self.areas
+    }
+
+    /// Adds an area of `size` bytes at `start`, both rounded to whole pages.
+    ///
+    /// Physically backed areas are mapped right away, anonymous areas on the first access.
+    pub fn map(
+        &mut self,
+        start: usize,
+        size: usize,
+        flags: MappingFlags,
+        backing: Backing,
+    ) -> PagingResult {
+        let vma = Vma {
+            start: align_down_4k(start),
+            end: align_up_4k(start + size),
+            flags,
+            backing,
+        };
+        self.areas.insert(vma)?;
+
+        if let Backing::Physical(paddr) = backing {
+            let offset = vma.start - align_down_4k(paddr);
+            let result = self.page_table.map_region(
+                VirtAddr::from(vma.start),
+                |vaddr| PhysAddr::from(vaddr.as_usize() - offset),
+                vma.end - vma.start,
+                flags,
+                true,
+            );
+            if let Err(e) = result {
+                self.unmap(vma.start)?;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Maps frames to every page of `size` bytes at `start` that is not mapped yet, instead of
+    /// waiting for page faults.
+    ///
+    /// The range has to lie within anonymous areas.
+    pub fn populate(&mut self, start: usize, size: usize) -> PagingResult {
+        let mut page = align_down_4k(start);
+        while page < start + size {
+            let vma = self.areas.find(page).ok_or(PagingError::NotMapped)?;
+            if vma.backing != Backing::Anonymous {
+                return Err(PagingError::NotMapped);
+            }
+            let flags = vma.flags;
+            if self.page_table.query(VirtAddr::from(page)).is_err() {
+                self.map_zeroed(VirtAddr::from(page), flags)?;
+            }
+            page += PAGE_SIZE_4K;
+        }
+        Ok(())
+    }
+
+    /// Reserves an area of `size` bytes at `start` whose pages of `page_size` are mapped by the
+    /// caller through the returned [`LeafTable`].
+    ///
+    /// The area has to be aligned to its size and covered by a single table.
+    pub fn reserve(
+        &mut self,
+        start: usize,
+        size: usize,
+        page_size: PageSize,
+        flags: MappingFlags,
+    ) -> PagingResult<LeafTable> {
+        let table = self
+            .page_table
+            .leaf_table(VirtAddr::from(start), page_size)?;
+        if size != table.size() {
+            return Err(PagingError::NotAligned);
+        }
+        self.areas.insert(Vma {
+            start,
+            end: start + size,
+            flags,
+            backing: Backing::External,
+        })?;
+        Ok(table)
+    }
+
+    /// Removes the area starting at `start` and unmaps its pages on all harts.
+    ///
+    /// Frames of anonymous areas are freed, the others belong to the caller.
+    pub fn unmap(&mut self, start: usize) -> PagingResult {
+        let vma = self.areas.remove(start).ok_or(PagingError::NotMapped)?;
+        // Frames are only freed once no hart can reach them anymore
+        let mut frames = Vec::new();
+        let mut page = vma.start;
+        let mut result = Ok(());
+        while page < vma.end {
+            match self.page_table.unmap(VirtAddr::from(page)) {
+                Ok((paddr, size, flush)) => {
+                    flush.ignore();
+                    if vma.backing == Backing::Anonymous {
+                        frames.push(paddr);
+                    }
+                    page += size as usize;
+                }
+                Err(PagingError::NotMapped) => page += PAGE_SIZE_4K,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.shootdown(vma.start, vma.end - vma.start);
+        for frame in frames {
+            FrameAllocator::dealloc_frame(frame);
+        }
+        result
+    }
+
+    /// Changes the flags of the `size` bytes at `start`, both rounded to whole pages.
+    ///
+    /// The range has to lie within mapped areas. Areas and huge pages that are only partly
+    /// inside of it are split, so the rest of them keeps its flags. Copy-on-write pages stay
+    /// read-only, the first store copies them as usual.
+    pub fn protect(&mut self, start: usize, size: usize, flags: MappingFlags) -> PagingResult {
+        let (start, end) = (align_down_4k(start), align_up_4k(start + size));
+        let mut vaddr = start;
+        while vaddr < end {
+            let vma = self.areas.find(vaddr).ok_or(PagingError::NotMapped)?;
+            vaddr = vma.end;
+        }
+
+        self.areas.split(start);
+        self.areas.split(end);
+        let mut vaddr = start;
+        while vaddr < end {
+            let vma = self.areas.get_mut(vaddr).unwrap();
+            vma.flags = flags;
+            vaddr = vma.end;
+        }
+        let result = self
+            .page_table
+            .protect_region(VirtAddr::from(start), end - start, flags);
+        self.shootdown(start, end - start);
+        result
+    }
+
+    /// Makes `space` the active address space of the current hart, which its page faults are
+    /// resolved in from now on.
+    ///
+    /// # Safety
+    /// The kernel must be mapped in this address space at the same addresses as in the current
+    /// one.
+    pub unsafe fn switch(space: &Arc<Mutex<AddressSpace>>) {
+        let mut current = CURRENT[stack::current_hart()].lock();
+        {
+            let mut space = space.lock();
+            let asid = tlb::activate(space.asid);
+            space.asid = Some(asid);
+            let mut reg = Satp::from_bits(0);
+            reg.set_mode(space.page_table.mode().satp_mode());
+            reg.set_asid(asid.value() as usize);
+            reg.set_ppn(space.page_table.root_paddr().as_usize() >> 12);
+            unsafe { satp::write(reg) };
+        }
+        // The previous address space may be dropped here, it is not active anymore
+        let previous = current.replace(space.clone());
+        drop(current);
+        drop(previous);
+    }
+
+    /// Flushes the TLB entries for the `size` bytes at `start` of this address space on every
+    /// hart.
+    fn shootdown(&self, start: usize, size: usize) {
+        // Without an ASID the address space never ran, so nothing is cached
+        if let Some(asid) = self.asid() {
+            tlb::shootdown(Some(asid), start, size);
+        }
+    }
+
+    /// Resolves a page fault at `vaddr` by mapping the page if it belongs to an area, or by
+    /// copying it if it is a copy-on-write page that is written to.
+    ///
+    /// Returns false if the access is a real violation, either outside of any area, not permitted
+    /// by the flags of the area or to a page that is already mapped. Faults from user mode, as
+    /// told by `user`, are only resolved in user areas.
+    pub fn handle_page_fault(&mut self, vaddr: usize, access: Access, user: bool) -> bool {
+        let Some(vma) = self.areas.find(vaddr).copied() else {
+            return false;
+        };
+        if !vma.allows(access, user) {
+            return false;
+        }
+
+        let page = VirtAddr::from_usize(align_down_4k(vaddr));
+        let result = match self.page_table.entry(page) {
+            Ok((entry, _)) if access == Access::Write && entry.is_cow() => {
+                self.resolve_cow(page, vma.flags)
+            }
+            Ok(_) => return false,
+            Err(_) => match vma.backing {
+                Backing::Anonymous => self.map_zeroed(page, vma.flags),
+                // Physically backed areas are mapped up front, external ones by their owner
+                Backing::Physical(_) | Backing::External => return false,
+            },
+        };
+        match result {
+            Ok(()) => true,
+            Err(PagingError::NoMemory) => {
+                println!("Out of memory while handling page fault at {:#x}", vaddr);
+                false
+            }
+            Err(e) => {
+                println!("Failed to map {:#x}: {:?}", vaddr, e);
+                false
+            }
+        }
+    }
+
+    /// Clones the address space, as for `fork`.
+    ///
+    /// The upper half, which holds the kernel, is shared with the child through the root table,
+    /// so the kernel heap and every other kernel area stay mapped when the child runs. Root
+    /// entries the kernel creates later are copied on the first fault, see
+    /// [`AddressSpace::sync_kernel_half`].
+    ///
+    /// In the lower half, pages of anonymous user areas are shared read-only and marked
+    /// copy-on-write in both address spaces, the first write copies them. This includes
+    /// read-only areas, which may be made writable later. The other anonymous pages are shared
+    /// as they are and physically backed areas are mapped again. Areas of an external owner are
+    /// left out, the owner maps them in its own address space only.
+    pub fn fork(&mut self) -> PagingResult<AddressSpace> {
+        let mut child = AddressSpace::new(self.page_table.mode())?;
+        child.page_table.share_kernel_half(&self.page_table);
+        let areas: Vec<Vma> = self.areas.iter().copied().collect();
+        for vma in areas {
+            if page_table::is_upper_half(vma.start) {
+                continue;
+            }
+            match vma.backing {
+                Backing::Anonymous => {
+                    child.areas.insert(vma)?;
+                    self.share_pages(&mut child, vma)?;
+                }
+                Backing::Physical(_) => {
+                    child.map(vma.start, vma.end - vma.start, vma.flags, vma.backing)?
+                }
+                Backing::External => {}
+            }
+        }
+        // The copy-on-write pages are not writable anymore
+        self.shootdown(0, usize::MAX);
+        Ok(child)
+    }
+
+    /// Copies the root entry for the kernel address `vaddr` from `kernel`, for a fault raised
+    /// because the kernel created it after the upper half was shared by [`AddressSpace::fork`].
+    ///
+    /// Returns false if the entry was already there, so the fault has another cause.
+    pub fn sync_kernel_half(&mut self, kernel: &AddressSpace, vaddr: usize) -> bool {
+        match self
+            .page_table
+            .sync_kernel_half(&kernel.page_table, VirtAddr::from(vaddr))
+        {
+            Some(flush) => {
+                flush.flush();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Maps the pages of the anonymous area `vma` into `child` as well, taking a reference to
+    /// every frame.
+    fn share_pages(&mut self, child: &mut AddressSpace, vma: Vma) -> PagingResult {
+        let cow = vma.flags.contains(MappingFlags::USER);
+        let mut page = vma.start;
+        while page < vma.end {
+            let vaddr = VirtAddr::from(page);
+            let (paddr, size) = match self.page_table.query(vaddr) {
+                Ok(_) if cow => {
+                    // The parent is flushed once all of its pages are marked
+                    let (paddr, flags, size, flush) = self.page_table.mark_cow(vaddr)?;
+                    flush.ignore();
+                    child
+                        .page_table
+                        .map_cow(vaddr, paddr, size, flags)?
+                        .ignore();
+                    (paddr, size)
+                }
+                Ok((paddr, flags, size)) => {
+                    child.page_table.map(vaddr, paddr, size, flags)?.ignore();
+                    (paddr, size)
+                }
+                Err(PagingError::NotMapped) => {
+                    page += PAGE_SIZE_4K;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            FrameAllocator::get(paddr);
+            page += size as usize;
+        }
+        Ok(())
+    }
+
+    /// Gives the copy-on-write page at `page` a frame of its own, so it can be written to.
+    ///
+    /// The last address space sharing the frame takes it over in place, the others copy it.
+    fn resolve_cow(&mut self, page: VirtAddr, flags: MappingFlags) -> PagingResult {
+        let (entry, size) = self.page_table.entry(page)?;
+        if size != PageSize::Size4K {
+            return Err(PagingError::MappedToHugePage);
+        }
+        let shared = entry.paddr();
+        let frame = FrameAllocator::page(shared).ok_or(PagingError::NotMapped)?;
+        let target = if frame.refcount() == 1 {
+            shared
+        } else {
+            let copy = FrameAllocator::alloc(PageFlags::USER).ok_or(PagingError::NoMemory)?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    FrameAllocator::phys_to_virt(shared).as_ptr(),
+                    FrameAllocator::phys_to_virt(copy).as_mut_ptr(),
+                    PAGE_SIZE_4K,
+                );
+            }
+            copy
+        };
+        FrameAllocator::page(target)
+            .unwrap()
+            .set_owner(self.page_table.root_paddr().as_usize());
+        self.page_table.remap(page, target, flags)?.ignore();
+        // Other harts may still hold the read-only entry
+        self.shootdown(page.as_usize(), PAGE_SIZE_4K);
+        if target != shared {
+            FrameAllocator::put(shared);
+        }
+        Ok(())
+    }
+
+    /// Maps a zeroed frame to `page`, owned by this address space.
+    fn map_zeroed(&mut self, page: VirtAddr, flags: MappingFlags) -> PagingResult {
+        let usage = if flags.contains(MappingFlags::USER) {
+            PageFlags::USER
+        } else {
+            PageFlags::KERNEL
+        };
+        let frame = FrameAllocator::alloc(usage).ok_or(PagingError::NoMemory)?;
+        // The root table identifies the address space, it does not move with it
+        FrameAllocator::page(frame)
+            .unwrap()
+            .set_owner(self.page_table.root_paddr().as_usize());
+        unsafe {
+            core::ptr::write_bytes(
+                FrameAllocator::phys_to_virt(frame).as_mut_ptr(),
+                0,
+                PAGE_SIZE_4K,
+            );
+        }
+        match self.page_table.map(page, frame, PageSize::Size4K, flags) {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(e) => {
+                FrameAllocator::dealloc_frame(frame);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Unmaps every area, dropping the references to the frames of anonymous areas.
+    ///
+    /// Harts keep the address space they run in alive, so it is not active anymore.
+    fn drop(&mut self) {
+        let starts: Vec<usize> = self.areas.iter().map(|vma| vma.start).collect();
+        for start in starts {
+            if let Err(e) = self.unmap(start) {
+                println!("Failed to unmap {:#x}: {:?}", start, e);
+            }
+        }
+    }
+}
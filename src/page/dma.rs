@@ -0,0 +1,208 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use memory_addr::{PAGE_SIZE_4K, PhysAddr, VirtAddr, align_down_4k, align_up_4k};
+use page_table_multiarch::{MappingFlags, PagingError, PagingResult};
+
+use super::page_table;
+use super::vma::Backing;
+use super::vmalloc::{VMALLOC_GUARD_SIZE, VMALLOC_SIZE, VMALLOC_START};
+use super::{FrameAllocator, LINEAR_MAP_SIZE, PHYS_VIRT_OFFSET, kernel_space, phys_to_virt};
+use crate::{device_tree, println};
+use wiheom_lib::page::PageFlags;
+
+/// Size of the cache blocks of the Zicbom operations, 0 if the harts do not implement Zicbom.
+static CBOM_BLOCK_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Who accesses a streaming DMA buffer during the transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Direction {
+    /// The device reads data written by the CPU.
+    ToDevice,
+    /// The device writes data the CPU reads afterwards.
+    FromDevice,
+    /// The device reads and writes.
+    Bidirectional,
+}
+
+#[derive(Clone, Copy)]
+enum CacheOp {
+    /// Writes dirty blocks back to memory.
+    Clean,
+    /// Writes dirty blocks back to memory and drops them.
+    Flush,
+    /// Drops blocks without writing them back.
+    Invalidate,
+}
+
+/// Enables the Svpbmt memory types and Zicbom cache operations if every hart supports them.
+///
+/// Has to be called before the kernel address space is built, so its device mappings are
+/// uncached.
+pub fn init() {
+    if device_tree::has_isa_extension("svpbmt") {
+        page_table::enable_svpbmt();
+        println!("Svpbmt: coherent DMA buffers and devices are mapped uncached");
+    }
+    if device_tree::has_isa_extension("zicbom") {
+        match device_tree::cbom_block_size() {
+            Some(size) if size.is_power_of_two() => {
+                CBOM_BLOCK_SIZE.store(size, Ordering::Relaxed);
+                println!("Zicbom: {} byte cache blocks", size);
+            }
+            _ => {
+                println!("Zicbom without a valid riscv,cbom-block-size, not using it");
+            }
+        }
+    }
+}
+
+/// Allocates a zeroed, physically contiguous and page aligned buffer of `size` bytes for
+/// coherent DMA, and returns its virtual and physical address.
+///
+/// With Svpbmt the buffer gets an uncached mapping in the vmalloc range. Otherwise it is used
+/// through the linear map, which is only coherent if the platform keeps DMA coherent with the
+/// caches, like QEMU does. Returns `None` if there is no contiguous memory left.
+#[allow(dead_code)]
+pub fn dma_alloc(size: usize) -> Option<(VirtAddr, PhysAddr)> {
+    let size = align_up_4k(size.max(1));
+    let paddr = FrameAllocator::alloc_contiguous(size / PAGE_SIZE_4K, 1, PageFlags::KERNEL)?;
+    let linear = phys_to_virt(paddr.as_usize());
+    unsafe { (linear as *mut u8).write_bytes(0, size) };
+    // Dirty blocks of the linear map must not be written back over data from the device
+    cache_op(CacheOp::Flush, linear, size);
+    if !page_table::svpbmt() {
+        return Some((VirtAddr::from(linear), paddr));
+    }
+
+    let mut space = kernel_space().lock();
+    let result = space
+        .areas()
+        .find_free(
+            VMALLOC_START,
+            VMALLOC_START + VMALLOC_SIZE,
+            size,
+            VMALLOC_GUARD_SIZE,
+        )
+        .ok_or(PagingError::NoMemory)
+        .and_then(|start| {
+            space.map(
+                start,
+                size,
+                MappingFlags::READ | MappingFlags::WRITE | MappingFlags::UNCACHED,
+                Backing::Physical(paddr.as_usize()),
+            )?;
+            Ok(start)
+        });
+    match result {
+        Ok(start) => Some((VirtAddr::from(start), paddr)),
+        Err(e) => {
+            println!("DMA allocation of {:#x} bytes failed: {:?}", size, e);
+            FrameAllocator::dealloc_contiguous(paddr, size / PAGE_SIZE_4K);
+            None
+        }
+    }
+}
+
+/// Frees a buffer of [`dma_alloc`].
+///
+/// # Safety
+/// `vaddr` and `paddr` must have been returned by [`dma_alloc`] for `size`, and neither the CPU
+/// nor a device may use the buffer afterwards.
+#[allow(dead_code)]
+pub unsafe fn dma_free(vaddr: VirtAddr, paddr: PhysAddr, size: usize) -> PagingResult {
+    let start = vaddr.as_usize();
+    if (VMALLOC_START..VMALLOC_START + VMALLOC_SIZE).contains(&start) {
+        kernel_space().lock().unmap(start)?;
+    } else if start != phys_to_virt(paddr.as_usize()) {
+        return Err(PagingError::NotMapped);
+    }
+    FrameAllocator::dealloc_contiguous(paddr, align_up_4k(size.max(1)) / PAGE_SIZE_4K);
+    Ok(())
+}
+
+/// Hands the `size` bytes at `vaddr` to a device for a transfer in `direction` and returns
+/// their physical address.
+///
+/// The CPU must not touch the buffer until [`dma_unmap`]. Buffers the device writes to should
+/// not share cache blocks with other data, as those blocks are dropped afterwards. Returns
+/// `None` if the buffer is not mapped or not physically contiguous.
+#[allow(dead_code)]
+pub fn dma_map(vaddr: VirtAddr, size: usize, direction: Direction) -> Option<PhysAddr> {
+    let paddr = physical_range(vaddr.as_usize(), size)?;
+    // The device has to see the data of the CPU, and dirty blocks must not be evicted over the
+    // data of the device later
+    let op = match direction {
+        Direction::ToDevice | Direction::Bidirectional => CacheOp::Clean,
+        Direction::FromDevice => CacheOp::Flush,
+    };
+    cache_op(op, vaddr.as_usize(), size);
+    Some(paddr)
+}
+
+/// Hands a buffer of [`dma_map`] back to the CPU once the transfer is done.
+#[allow(dead_code)]
+pub fn dma_unmap(vaddr: VirtAddr, size: usize, direction: Direction) {
+    if direction != Direction::ToDevice {
+        // Blocks fetched speculatively during the transfer may hold stale data
+        cache_op(CacheOp::Invalidate, vaddr.as_usize(), size);
+    }
+}
+
+/// The physical address of the `size` bytes at `vaddr`, if they are mapped to contiguous memory.
+fn physical_range(vaddr: usize, size: usize) -> Option<PhysAddr> {
+    if (PHYS_VIRT_OFFSET..PHYS_VIRT_OFFSET + LINEAR_MAP_SIZE).contains(&vaddr) {
+        return Some(PhysAddr::from(super::virt_to_phys(vaddr)));
+    }
+    let (start, _) = super::translate(vaddr)?.target()?;
+    let mut page = align_down_4k(vaddr) + PAGE_SIZE_4K;
+    while page < vaddr + size {
+        let (paddr, _) = super::translate(page)?.target()?;
+        if paddr.as_usize() != start.as_usize() + (page - vaddr) {
+            return None;
+        }
+        page += PAGE_SIZE_4K;
+    }
+    Some(start)
+}
+
+/// Applies `op` to every cache block of the `size` bytes at `vaddr`, if Zicbom is available.
+fn cache_op(op: CacheOp, vaddr: usize, size: usize) {
+    let block = CBOM_BLOCK_SIZE.load(Ordering::Relaxed);
+    if block == 0 {
+        return;
+    }
+    let mut addr = vaddr & !(block - 1);
+    while addr < vaddr + size {
+        unsafe {
+            match op {
+                CacheOp::Clean => asm!(
+                    ".option push",
+                    ".option arch, +zicbom",
+                    "cbo.clean ({})",
+                    ".option pop",
+                    in(reg) addr,
+                    options(nostack)
+                ),
+                CacheOp::Flush => asm!(
+                    ".option push",
+                    ".option arch, +zicbom",
+                    "cbo.flush ({})",
+                    ".option pop",
+                    in(reg) addr,
+                    options(nostack)
+                ),
+                CacheOp::Invalidate => asm!(
+                    ".option push",
+                    ".option arch, +zicbom",
+                    "cbo.inval ({})",
+                    ".option pop",
+                    in(reg) addr,
+                    options(nostack)
+                ),
+            }
+        }
+        addr += block;
+    }
+}
@@ -0,0 +1,956 @@
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use bitflags::bitflags;
+use memory_addr::{MemoryAddr, PAGE_SIZE_4K, PhysAddr, VirtAddr};
+use page_table_multiarch::{MappingFlags, PageSize, PagingError, PagingHandler, PagingResult};
+use riscv::register::satp::Mode;
+
+use super::FrameAllocator;
+
+/// Number of entries in a page table of any level.
+const ENTRY_COUNT: usize = 512;
+
+/// Index of the first root entry of the upper half, where the kernel lives.
+const KERNEL_HALF: usize = ENTRY_COUNT / 2;
+
+/// Whether the canonical address `vaddr` lies in the upper half, which is the same for all
+/// paging modes.
+pub const fn is_upper_half(vaddr: usize) -> bool {
+    (vaddr as isize) < 0
+}
+
+/// The RISC-V virtual memory schemes the kernel can run with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PagingMode {
+    Sv39,
+    Sv48,
+    Sv57,
+}
+
+impl PagingMode {
+    /// Parses the `mmu-type` property of a cpu node.
+    pub fn from_mmu_type(mmu_type: &str) -> Option<Self> {
+        match mmu_type {
+            "riscv,sv39" => Some(Self::Sv39),
+            "riscv,sv48" => Some(Self::Sv48),
+            "riscv,sv57" => Some(Self::Sv57),
+            _ => None,
+        }
+    }
+
+    /// The next narrower mode, every RV64 MMU has to support Sv39.
+    pub const fn narrower(self) -> Option<Self> {
+        match self {
+            Self::Sv39 => None,
+            Self::Sv48 => Some(Self::Sv39),
+            Self::Sv57 => Some(Self::Sv48),
+        }
+    }
+
+    /// Number of page table levels.
+    pub const fn levels(self) -> usize {
+        match self {
+            Self::Sv39 => 3,
+            Self::Sv48 => 4,
+            Self::Sv57 => 5,
+        }
+    }
+
+    /// Number of significant bits of a virtual address.
+    pub const fn va_bits(self) -> usize {
+        12 + 9 * self.levels()
+    }
+
+    pub const fn satp_mode(self) -> Mode {
+        match self {
+            Self::Sv39 => Mode::Sv39,
+            Self::Sv48 => Mode::Sv48,
+            Self::Sv57 => Mode::Sv57,
+        }
+    }
+
+    /// The mode of a `satp` value, `None` if paging is off or the mode is not supported.
+    pub const fn from_satp_mode(mode: Mode) -> Option<Self> {
+        match mode {
+            Mode::Sv39 => Some(Self::Sv39),
+            Mode::Sv48 => Some(Self::Sv48),
+            Mode::Sv57 => Some(Self::Sv57),
+            _ => None,
+        }
+    }
+
+    /// Bit position of the index of `level` in a virtual address.
+    const fn shift(self, level: usize) -> usize {
+        12 + 9 * (self.levels() - 1 - level)
+    }
+
+    /// Index of the entry for `vaddr` in a table of `level`.
+    const fn index(self, vaddr: usize, level: usize) -> usize {
+        (vaddr >> self.shift(level)) % ENTRY_COUNT
+    }
+
+    /// Number of bytes mapped by a leaf entry of `level`.
+    const fn leaf_size(self, level: usize) -> usize {
+        1 << self.shift(level)
+    }
+
+    /// Size of the pages mapped by leaf entries of `level`.
+    ///
+    /// Returns [`PagingError::MappedToHugePage`] for the 512G and 256T pages of Sv48 and Sv57,
+    /// which the kernel does not support.
+    const fn page_size(self, level: usize) -> PagingResult<PageSize> {
+        match self.levels() - 1 - level {
+            0 => Ok(PageSize::Size4K),
+            1 => Ok(PageSize::Size2M),
+            2 => Ok(PageSize::Size1G),
+            _ => Err(PagingError::MappedToHugePage),
+        }
+    }
+
+    /// Whether the upper bits of `vaddr` are a sign extension of the highest significant bit.
+    pub const fn is_canonical(self, vaddr: usize) -> bool {
+        self.canonicalize(vaddr) == vaddr
+    }
+
+    /// Sign extends the significant bits of `vaddr`.
+    const fn canonicalize(self, vaddr: usize) -> usize {
+        let shift = usize::BITS as usize - self.va_bits();
+        ((vaddr << shift) as isize >> shift) as usize
+    }
+}
+
+/// Whether the harts implement Svpbmt, otherwise the memory type bits are reserved and have to
+/// stay clear.
+static SVPBMT: AtomicBool = AtomicBool::new(false);
+
+/// Lets [`MappingFlags::DEVICE`] and [`MappingFlags::UNCACHED`] select the memory type of pages
+/// mapped from now on.
+pub fn enable_svpbmt() {
+    SVPBMT.store(true, Ordering::Relaxed);
+}
+
+/// Whether mappings can be made uncached with Svpbmt.
+pub fn svpbmt() -> bool {
+    SVPBMT.load(Ordering::Relaxed)
+}
+
+bitflags! {
+    /// Bits of a RISC-V page table entry.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PteFlags: u64 {
+        const V = 1 << 0;
+        const R = 1 << 1;
+        const W = 1 << 2;
+        const X = 1 << 3;
+        const U = 1 << 4;
+        const G = 1 << 5;
+        const A = 1 << 6;
+        const D = 1 << 7;
+        /// Software bit of a page that is shared read-only until the next write copies it.
+        const COW = 1 << 8;
+        /// Svpbmt non-cacheable, idempotent main memory.
+        const NC = 1 << 61;
+        /// Svpbmt non-cacheable, strongly ordered I/O memory.
+        const IO = 1 << 62;
+    }
+}
+
+impl fmt::Display for PteFlags {
+    /// Prints the bits as `VRWXUGAD`, with a `-` for every cleared bit, followed by the memory
+    /// type if it is not the default.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (flag, name) in Self::all().iter().zip("VRWXUGAD".chars()) {
+            f.write_char(if self.contains(flag) { name } else { '-' })?;
+        }
+        if self.contains(Self::COW) {
+            f.write_str(" COW")?;
+        }
+        if self.contains(Self::IO) {
+            f.write_str(" IO")?;
+        } else if self.contains(Self::NC) {
+            f.write_str(" NC")?;
+        }
+        Ok(())
+    }
+}
+
+impl From<MappingFlags> for PteFlags {
+    fn from(flags: MappingFlags) -> Self {
+        let mut pte = Self::empty();
+        pte.set(Self::R, flags.contains(MappingFlags::READ));
+        pte.set(Self::W, flags.contains(MappingFlags::WRITE));
+        pte.set(Self::X, flags.contains(MappingFlags::EXECUTE));
+        pte.set(Self::U, flags.contains(MappingFlags::USER));
+        if svpbmt() {
+            if flags.contains(MappingFlags::DEVICE) {
+                pte |= Self::IO;
+            } else if flags.contains(MappingFlags::UNCACHED) {
+                pte |= Self::NC;
+            }
+        }
+        pte
+    }
+}
+
+impl From<PteFlags> for MappingFlags {
+    fn from(pte: PteFlags) -> Self {
+        let mut flags = Self::empty();
+        flags.set(Self::READ, pte.contains(PteFlags::R));
+        flags.set(Self::WRITE, pte.contains(PteFlags::W));
+        flags.set(Self::EXECUTE, pte.contains(PteFlags::X));
+        flags.set(Self::USER, pte.contains(PteFlags::U));
+        flags.set(Self::DEVICE, pte.contains(PteFlags::IO));
+        flags.set(Self::UNCACHED, pte.contains(PteFlags::NC));
+        flags
+    }
+}
+
+/// A page table entry, the format is the same for all paging modes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct PageTableEntry(u64);
+
+impl PageTableEntry {
+    const PPN_MASK: u64 = ((1 << 44) - 1) << 10;
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// A leaf entry mapping `paddr`.
+    ///
+    /// The accessed and dirty bits are set up front, so the hardware never has to update them.
+    pub fn new_page(paddr: PhysAddr, flags: MappingFlags) -> Self {
+        let flags = PteFlags::from(flags) | PteFlags::V | PteFlags::A | PteFlags::D;
+        Self(flags.bits() | Self::ppn_bits(paddr))
+    }
+
+    /// A non-leaf entry pointing to the next level table at `paddr`.
+    pub fn new_table(paddr: PhysAddr) -> Self {
+        Self(PteFlags::V.bits() | Self::ppn_bits(paddr))
+    }
+
+    fn ppn_bits(paddr: PhysAddr) -> u64 {
+        ((paddr.as_usize() as u64) >> 2) & Self::PPN_MASK
+    }
+
+    pub fn paddr(self) -> PhysAddr {
+        PhysAddr::from(((self.0 & Self::PPN_MASK) << 2) as usize)
+    }
+
+    /// The same entry pointing to `paddr` instead.
+    pub fn with_paddr(self, paddr: PhysAddr) -> Self {
+        Self((self.0 & !Self::PPN_MASK) | Self::ppn_bits(paddr))
+    }
+
+    pub fn pte_flags(self) -> PteFlags {
+        PteFlags::from_bits_truncate(self.0)
+    }
+
+    pub fn flags(self) -> MappingFlags {
+        self.pte_flags().into()
+    }
+
+    /// Replaces the permissions and memory type of a leaf entry, all other bits are kept.
+    ///
+    /// Copy-on-write pages stay read-only, so the next store still copies them before the frame
+    /// is written to.
+    pub fn set_flags(&mut self, flags: MappingFlags) {
+        let mask =
+            (PteFlags::R | PteFlags::W | PteFlags::X | PteFlags::U | PteFlags::NC | PteFlags::IO)
+                .bits();
+        let mut bits = PteFlags::from(flags);
+        if self.is_cow() {
+            bits.remove(PteFlags::W);
+        }
+        self.0 = (self.0 & !mask) | bits.bits();
+    }
+
+    /// Whether the page is shared read-only until it is written to.
+    pub fn is_cow(self) -> bool {
+        self.pte_flags().contains(PteFlags::COW)
+    }
+
+    pub fn is_unused(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn is_present(self) -> bool {
+        self.pte_flags().contains(PteFlags::V)
+    }
+
+    /// Whether the entry maps a page instead of pointing to the next level table.
+    pub fn is_leaf(self) -> bool {
+        self.pte_flags()
+            .intersects(PteFlags::R | PteFlags::W | PteFlags::X)
+    }
+
+    pub fn clear(&mut self) {
+        self.0 = 0;
+    }
+}
+
+impl fmt::Debug for PageTableEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PageTableEntry")
+            .field("paddr", &self.paddr())
+            .field("flags", &self.pte_flags())
+            .finish()
+    }
+}
+
+/// A changed mapping whose stale TLB entries still have to be flushed.
+#[must_use]
+pub struct TlbFlush(VirtAddr);
+
+impl TlbFlush {
+    pub fn flush(self) {
+        super::tlb::flush_page(self.0.as_usize());
+    }
+
+    /// Skips the flush, for when the whole TLB is flushed later anyway.
+    #[allow(dead_code)]
+    pub fn ignore(self) {}
+}
+
+/// The leaf table for an aligned range of a [`PageTable`], whose entries are filled in by the
+/// owner of the range without going through the page table.
+///
+/// This lets the kernel heap grow without taking the lock of the kernel address space, which
+/// may be held by the allocation that makes the heap grow.
+pub struct LeafTable {
+    table: PhysAddr,
+    start: VirtAddr,
+    page_size: PageSize,
+}
+
+impl LeafTable {
+    /// Number of bytes covered by the table.
+    pub fn size(&self) -> usize {
+        self.page_size as usize * ENTRY_COUNT
+    }
+
+    /// Maps the page at `vaddr` to `paddr`.
+    ///
+    /// # Safety
+    /// The page table must still be alive, and no other entry of the table may be changed
+    /// concurrently.
+    pub unsafe fn map(
+        &self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        flags: MappingFlags,
+    ) -> PagingResult<TlbFlush> {
+        let size = self.page_size;
+        if !size.is_aligned(vaddr.as_usize()) || !size.is_aligned(paddr.as_usize()) {
+            return Err(PagingError::NotAligned);
+        }
+        let offset = vaddr
+            .as_usize()
+            .checked_sub(self.start.as_usize())
+            .filter(|&offset| offset < self.size())
+            .ok_or(PagingError::NotMapped)?;
+        let entry = &mut PageTable::table_mut(self.table)[offset / size as usize];
+        if !entry.is_unused() {
+            return Err(PagingError::AlreadyMapped);
+        }
+        *entry = PageTableEntry::new_page(paddr, flags);
+        Ok(TlbFlush(vaddr))
+    }
+}
+
+/// A multi-level RISC-V page table for any of the supported [`PagingMode`]s.
+///
+/// Tables are allocated from the [`FrameAllocator`] and accessed through the linear map. Level 0
+/// is the root table, level `levels - 1` holds the 4K leaf entries.
+pub struct PageTable {
+    root: PhysAddr,
+    mode: PagingMode,
+    /// Whether the upper half of the root points to the tables of the kernel address space,
+    /// which are not freed with this table.
+    shares_kernel: bool,
+}
+
+impl PageTable {
+    /// Creates an empty page table for `mode`.
+    pub fn try_new(mode: PagingMode) -> PagingResult<Self> {
+        Ok(Self {
+            root: Self::alloc_table()?,
+            mode,
+            shares_kernel: false,
+        })
+    }
+
+    pub fn mode(&self) -> PagingMode {
+        self.mode
+    }
+
+    pub fn root_paddr(&self) -> PhysAddr {
+        self.root
+    }
+
+    /// Points the upper half of the root to the same tables as `kernel`, so the kernel mappings
+    /// are shared instead of copied.
+    ///
+    /// The tables stay owned by `kernel`. Mappings made in the upper half through either table
+    /// show up in both, so this table must not map anything there itself.
+    pub fn share_kernel_half(&mut self, kernel: &PageTable) {
+        assert_eq!(self.mode, kernel.mode, "paging modes of the tables differ");
+        Self::table_mut(self.root)[KERNEL_HALF..]
+            .copy_from_slice(&Self::table(kernel.root)[KERNEL_HALF..]);
+        self.shares_kernel = true;
+    }
+
+    /// Copies the root entry for the upper half address `vaddr` from `kernel`, if the kernel
+    /// created it after the upper half was shared.
+    ///
+    /// Returns `None` if the entry is already up to date, so the fault at `vaddr` is not caused
+    /// by a missing root entry.
+    pub fn sync_kernel_half(&mut self, kernel: &PageTable, vaddr: VirtAddr) -> Option<TlbFlush> {
+        if !self.shares_kernel || !is_upper_half(vaddr.as_usize()) {
+            return None;
+        }
+        let index = self.index(vaddr, 0);
+        let entry = Self::table(kernel.root)[index];
+        let own = &mut Self::table_mut(self.root)[index];
+        if !entry.is_present() || *own == entry {
+            return None;
+        }
+        *own = entry;
+        // The hart may have cached the missing entry
+        Some(TlbFlush(vaddr))
+    }
+
+    /// Maps the page of `size` at `vaddr` to `paddr`.
+    ///
+    /// Returns [`PagingError::AlreadyMapped`] if the page or a part of it is already mapped.
+    pub fn map(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        size: PageSize,
+        flags: MappingFlags,
+    ) -> PagingResult<TlbFlush> {
+        if !size.is_aligned(vaddr.as_usize()) || !size.is_aligned(paddr.as_usize()) {
+            return Err(PagingError::NotAligned);
+        }
+        let entry = self.entry_mut_or_create(vaddr, self.leaf_level(size))?;
+        if !entry.is_unused() {
+            return Err(PagingError::AlreadyMapped);
+        }
+        *entry = PageTableEntry::new_page(paddr, flags);
+        Ok(TlbFlush(vaddr))
+    }
+
+    /// Creates the table holding the entries for pages of `page_size` starting at `vaddr`, which
+    /// has to be aligned to the range covered by the table.
+    pub fn leaf_table(&mut self, vaddr: VirtAddr, page_size: PageSize) -> PagingResult<LeafTable> {
+        let level = self.leaf_level(page_size);
+        if !vaddr.is_aligned(1usize << (self.shift(level) + 9)) {
+            return Err(PagingError::NotAligned);
+        }
+        Ok(LeafTable {
+            table: self.table_or_create(vaddr, level)?,
+            start: vaddr,
+            page_size,
+        })
+    }
+
+    /// Maps `size` bytes at `vaddr` to the frames returned by `paddr`.
+    ///
+    /// With `allow_huge`, every part of the region where both addresses are suitably aligned is
+    /// mapped with 1G or 2M pages, `paddr` has to be physically contiguous over such a page.
+    pub fn map_region(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: impl Fn(VirtAddr) -> PhysAddr,
+        size: usize,
+        flags: MappingFlags,
+        allow_huge: bool,
+    ) -> PagingResult {
+        if !vaddr.is_aligned_4k() || !size.is_multiple_of(PAGE_SIZE_4K) {
+            return Err(PagingError::NotAligned);
+        }
+        let mut offset = 0;
+        while offset < size {
+            let page = vaddr + offset;
+            let target = paddr(page);
+            let page_size = [PageSize::Size1G, PageSize::Size2M]
+                .into_iter()
+                .filter(|_| allow_huge)
+                .find(|&huge| {
+                    huge.is_aligned(page.as_usize())
+                        && huge.is_aligned(target.as_usize())
+                        && size - offset >= huge as usize
+                })
+                .unwrap_or(PageSize::Size4K);
+            self.map(page, target, page_size, flags)?.flush();
+            offset += page_size as usize;
+        }
+        Ok(())
+    }
+
+    /// Removes the mapping of the page containing `vaddr`.
+    ///
+    /// Returns the frame and size of the page that was mapped.
+    pub fn unmap(&mut self, vaddr: VirtAddr) -> PagingResult<(PhysAddr, PageSize, TlbFlush)> {
+        let (entry, size) = self.entry_mut(vaddr)?;
+        let paddr = entry.paddr();
+        entry.clear();
+        Ok((paddr, size, TlbFlush(vaddr)))
+    }
+
+    /// Changes the permissions of the page containing `vaddr`.
+    pub fn protect(
+        &mut self,
+        vaddr: VirtAddr,
+        flags: MappingFlags,
+    ) -> PagingResult<(PageSize, TlbFlush)> {
+        let (entry, size) = self.entry_mut(vaddr)?;
+        entry.set_flags(flags);
+        Ok((size, TlbFlush(vaddr)))
+    }
+
+    /// Changes the permissions of the `size` bytes at `vaddr`.
+    ///
+    /// Huge pages that are only partly inside the region are split first, so the pages outside
+    /// of it keep their permissions. Unmapped pages are skipped.
+    pub fn protect_region(
+        &mut self,
+        vaddr: VirtAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> PagingResult {
+        if !vaddr.is_aligned_4k() || !size.is_multiple_of(PAGE_SIZE_4K) {
+            return Err(PagingError::NotAligned);
+        }
+        let end = vaddr + size;
+        let mut page = vaddr;
+        while page < end {
+            let page_size = match self.entry(page) {
+                Ok((_, page_size)) => page_size,
+                Err(PagingError::NotMapped) => {
+                    page += PAGE_SIZE_4K;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let base = page.align_down(page_size as usize);
+            if base < vaddr || end < base + page_size as usize {
+                self.split(page)?.flush();
+                continue;
+            }
+            self.protect(page, flags)?.1.flush();
+            debug_assert!(
+                self.entry(page).is_ok_and(
+                    |(entry, _)| !entry.is_cow() || !entry.pte_flags().contains(PteFlags::W)
+                ),
+                "copy-on-write page {:#x} became writable",
+                page
+            );
+            page = base + page_size as usize;
+        }
+        Ok(())
+    }
+
+    /// Makes the page containing `vaddr` read-only and marks it copy-on-write.
+    ///
+    /// Returns the frame, the remaining flags and the size of the page.
+    pub fn mark_cow(
+        &mut self,
+        vaddr: VirtAddr,
+    ) -> PagingResult<(PhysAddr, MappingFlags, PageSize, TlbFlush)> {
+        let (entry, size) = self.entry_mut(vaddr)?;
+        entry.0 = (entry.0 & !PteFlags::W.bits()) | PteFlags::COW.bits();
+        Ok((
+            entry.paddr(),
+            entry.flags(),
+            size,
+            TlbFlush(vaddr.align_down(size as usize)),
+        ))
+    }
+
+    /// Maps the page of `size` at `vaddr` to `paddr` read-only, marked copy-on-write.
+    pub fn map_cow(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        size: PageSize,
+        flags: MappingFlags,
+    ) -> PagingResult<TlbFlush> {
+        let flush = self.map(vaddr, paddr, size, flags - MappingFlags::WRITE)?;
+        let (entry, _) = self.entry_mut(vaddr)?;
+        entry.0 |= PteFlags::COW.bits();
+        Ok(flush)
+    }
+
+    /// Points the page containing `vaddr` to `paddr` with `flags`, which also clears the
+    /// copy-on-write mark.
+    pub fn remap(
+        &mut self,
+        vaddr: VirtAddr,
+        paddr: PhysAddr,
+        flags: MappingFlags,
+    ) -> PagingResult<TlbFlush> {
+        let (entry, size) = self.entry_mut(vaddr)?;
+        *entry = PageTableEntry::new_page(paddr, flags);
+        Ok(TlbFlush(vaddr.align_down(size as usize)))
+    }
+
+    /// Replaces the huge page containing `vaddr` with a table of pages of the next smaller size
+    /// that map the same frames with the same flags.
+    ///
+    /// Returns [`PagingError::NotMapped`] if `vaddr` is not mapped by a huge page.
+    pub fn split(&mut self, vaddr: VirtAddr) -> PagingResult<TlbFlush> {
+        let (entry, size) = self.entry_mut(vaddr)?;
+        let child_size = match size {
+            PageSize::Size4K => return Err(PagingError::NotMapped),
+            PageSize::Size2M => PageSize::Size4K,
+            PageSize::Size1G => PageSize::Size2M,
+        };
+        let table = Self::alloc_table()?;
+        for (index, child) in Self::table_mut(table).iter_mut().enumerate() {
+            *child = entry.with_paddr(entry.paddr() + index * child_size as usize);
+        }
+        *entry = PageTableEntry::new_table(table);
+        Ok(TlbFlush(vaddr.align_down(size as usize)))
+    }
+
+    /// Translates `vaddr`, returning the physical address, the flags and the size of the page.
+    pub fn query(&self, vaddr: VirtAddr) -> PagingResult<(PhysAddr, MappingFlags, PageSize)> {
+        let (entry, size) = self.entry(vaddr)?;
+        let offset = size.align_offset(vaddr.as_usize());
+        Ok((entry.paddr() + offset, entry.flags(), size))
+    }
+
+    /// Calls `func` with the level, the virtual address and the entry of every present entry,
+    /// tables before the entries they contain.
+    pub fn walk(&self, mut func: impl FnMut(usize, VirtAddr, PageTableEntry)) {
+        self.walk_table(self.root, 0, 0, &mut func);
+    }
+
+    fn walk_table(
+        &self,
+        table: PhysAddr,
+        level: usize,
+        start: usize,
+        func: &mut impl FnMut(usize, VirtAddr, PageTableEntry),
+    ) {
+        for (index, &entry) in Self::table(table).iter().enumerate() {
+            if !entry.is_present() {
+                continue;
+            }
+            let vaddr = start + (index << self.shift(level));
+            func(level, VirtAddr::from(self.mode.canonicalize(vaddr)), entry);
+            if !entry.is_leaf() && level + 1 < self.mode.levels() {
+                self.walk_table(entry.paddr(), level + 1, vaddr, func);
+            }
+        }
+    }
+
+    /// The walk of the table for `vaddr`.
+    #[allow(dead_code)]
+    pub fn translate(&self, vaddr: VirtAddr) -> Translation {
+        unsafe { Translation::walk(self.root, self.mode, vaddr) }
+    }
+
+    /// Every mapping of the table, with contiguous runs of pages merged.
+    pub fn dump(&self) -> Dump<'_> {
+        Dump(self)
+    }
+
+    fn shift(&self, level: usize) -> usize {
+        self.mode.shift(level)
+    }
+
+    fn index(&self, vaddr: VirtAddr, level: usize) -> usize {
+        self.mode.index(vaddr.as_usize(), level)
+    }
+
+    /// The level whose entries map pages of `size`.
+    fn leaf_level(&self, size: PageSize) -> usize {
+        let levels = self.mode.levels();
+        match size {
+            PageSize::Size4K => levels - 1,
+            PageSize::Size2M => levels - 2,
+            PageSize::Size1G => levels - 3,
+        }
+    }
+
+    fn page_size(&self, level: usize) -> PagingResult<PageSize> {
+        self.mode.page_size(level)
+    }
+
+    /// The present leaf entry mapping `vaddr` and the size of its page.
+    pub fn entry(&self, vaddr: VirtAddr) -> PagingResult<(PageTableEntry, PageSize)> {
+        debug_assert!(self.mode.is_canonical(vaddr.as_usize()));
+        let mut table = self.root;
+        for level in 0..self.mode.levels() {
+            let entry = Self::table(table)[self.index(vaddr, level)];
+            if !entry.is_present() {
+                break;
+            }
+            if entry.is_leaf() {
+                return Ok((entry, self.page_size(level)?));
+            }
+            table = entry.paddr();
+        }
+        Err(PagingError::NotMapped)
+    }
+
+    fn entry_mut(&mut self, vaddr: VirtAddr) -> PagingResult<(&mut PageTableEntry, PageSize)> {
+        debug_assert!(self.mode.is_canonical(vaddr.as_usize()));
+        let mut table = self.root;
+        for level in 0..self.mode.levels() {
+            let entry = &mut Self::table_mut(table)[self.index(vaddr, level)];
+            if !entry.is_present() {
+                break;
+            }
+            if entry.is_leaf() {
+                return Ok((entry, self.page_size(level)?));
+            }
+            table = entry.paddr();
+        }
+        Err(PagingError::NotMapped)
+    }
+
+    /// The entry at `level` for `vaddr`, allocating the tables above it as needed.
+    fn entry_mut_or_create(
+        &mut self,
+        vaddr: VirtAddr,
+        level: usize,
+    ) -> PagingResult<&mut PageTableEntry> {
+        let table = self.table_or_create(vaddr, level)?;
+        Ok(&mut Self::table_mut(table)[self.index(vaddr, level)])
+    }
+
+    /// The table at `level` for `vaddr`, allocating it and the tables above it as needed.
+    fn table_or_create(&mut self, vaddr: VirtAddr, level: usize) -> PagingResult<PhysAddr> {
+        debug_assert!(self.mode.is_canonical(vaddr.as_usize()));
+        let mut table = self.root;
+        for depth in 0..level {
+            let entry = &mut Self::table_mut(table)[self.index(vaddr, depth)];
+            if entry.is_unused() {
+                *entry = PageTableEntry::new_table(Self::alloc_table()?);
+            } else if entry.is_leaf() {
+                return Err(PagingError::MappedToHugePage);
+            }
+            table = entry.paddr();
+        }
+        Ok(table)
+    }
+
+    fn alloc_table() -> PagingResult<PhysAddr> {
+        let paddr = FrameAllocator::alloc_frame().ok_or(PagingError::NoMemory)?;
+        Self::table_mut(paddr).fill(PageTableEntry::empty());
+        Ok(paddr)
+    }
+
+    fn table<'a>(paddr: PhysAddr) -> &'a [PageTableEntry] {
+        let ptr = FrameAllocator::phys_to_virt(paddr).as_ptr_of::<PageTableEntry>();
+        unsafe { core::slice::from_raw_parts(ptr, ENTRY_COUNT) }
+    }
+
+    fn table_mut<'a>(paddr: PhysAddr) -> &'a mut [PageTableEntry] {
+        let ptr = FrameAllocator::phys_to_virt(paddr).as_mut_ptr_of::<PageTableEntry>();
+        unsafe { core::slice::from_raw_parts_mut(ptr, ENTRY_COUNT) }
+    }
+
+    fn free_table(&self, table: PhysAddr, level: usize) {
+        if level + 1 < self.mode.levels() {
+            for &entry in Self::table(table) {
+                if entry.is_present() && !entry.is_leaf() {
+                    self.free_table(entry.paddr(), level + 1);
+                }
+            }
+        }
+        FrameAllocator::dealloc_frame(table);
+    }
+}
+
+impl Drop for PageTable {
+    /// Frees the tables, the mapped frames belong to whoever mapped them.
+    ///
+    /// A shared upper half is left to the kernel address space.
+    fn drop(&mut self) {
+        if !self.shares_kernel {
+            self.free_table(self.root, 0);
+            return;
+        }
+        for &entry in &Self::table(self.root)[..KERNEL_HALF] {
+            if entry.is_present() && !entry.is_leaf() {
+                self.free_table(entry.paddr(), 1);
+            }
+        }
+        FrameAllocator::dealloc_frame(self.root);
+    }
+}
+
+/// One entry visited by a [`Translation`].
+#[derive(Debug, Clone, Copy)]
+pub struct WalkStep {
+    pub level: usize,
+    pub table: PhysAddr,
+    pub index: usize,
+    pub entry: PageTableEntry,
+}
+
+/// The entries a page table walk for a virtual address visits, see [`PageTable::translate`].
+pub struct Translation {
+    vaddr: VirtAddr,
+    mode: PagingMode,
+    steps: [Option<WalkStep>; 5],
+}
+
+impl Translation {
+    /// Walks the page table of `mode` whose root table is at `root` for `vaddr`.
+    ///
+    /// # Safety
+    /// `root` has to be a valid page table of `mode`, like the one `satp` points to.
+    pub unsafe fn walk(root: PhysAddr, mode: PagingMode, vaddr: VirtAddr) -> Self {
+        let mut translation = Self {
+            vaddr,
+            mode,
+            steps: [None; 5],
+        };
+        if !mode.is_canonical(vaddr.as_usize()) {
+            return translation;
+        }
+        let mut table = root;
+        for level in 0..mode.levels() {
+            let index = mode.index(vaddr.as_usize(), level);
+            let entry = PageTable::table(table)[index];
+            translation.steps[level] = Some(WalkStep {
+                level,
+                table,
+                index,
+                entry,
+            });
+            if !entry.is_present() || entry.is_leaf() {
+                break;
+            }
+            table = entry.paddr();
+        }
+        translation
+    }
+
+    /// The visited entries, from the root table down.
+    pub fn steps(&self) -> impl Iterator<Item = &WalkStep> {
+        self.steps.iter().map_while(Option::as_ref)
+    }
+
+    /// The physical address `vaddr` translates to and the size of its page, if it is mapped.
+    pub fn target(&self) -> Option<(PhysAddr, PageSize)> {
+        let last = self.steps().last()?;
+        if !last.entry.is_present() || !last.entry.is_leaf() {
+            return None;
+        }
+        let size = self.mode.page_size(last.level).ok()?;
+        Some((
+            last.entry.paddr() + size.align_offset(self.vaddr.as_usize()),
+            size,
+        ))
+    }
+}
+
+impl fmt::Display for Translation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "translation of {:#x} ({:?})", self.vaddr, self.mode)?;
+        if !self.mode.is_canonical(self.vaddr.as_usize()) {
+            return write!(f, ": not canonical");
+        }
+        for step in self.steps() {
+            write!(
+                f,
+                "\n  level {}: {:#x}[{:3}] = {:#018x} {} -> {:#x}",
+                step.level,
+                step.table,
+                step.index,
+                step.entry.0,
+                step.entry.pte_flags(),
+                step.entry.paddr()
+            )?;
+        }
+        match self.target() {
+            Some((paddr, size)) => write!(
+                f,
+                "\n  mapped to {:#x} by a {} page",
+                paddr,
+                Size(size as usize)
+            ),
+            None => match self.steps().last() {
+                Some(last) if last.entry.is_present() && last.entry.is_leaf() => write!(
+                    f,
+                    "\n  mapped by an unsupported {} page",
+                    Size(self.mode.leaf_size(last.level))
+                ),
+                _ => write!(f, "\n  not mapped"),
+            },
+        }
+    }
+}
+
+/// The mappings of a [`PageTable`], see [`PageTable::dump`].
+pub struct Dump<'a>(&'a PageTable);
+
+impl fmt::Display for Dump<'_> {
+    /// Prints a line for every run of pages that are contiguous both virtually and physically and
+    /// have the same flags.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let print = |f: &mut fmt::Formatter<'_>, (vaddr, paddr, size, flags): Run| {
+            writeln!(
+                f,
+                "{:#018x}-{:#018x} -> {:#012x}-{:#012x} {} {}",
+                vaddr,
+                vaddr + size,
+                paddr,
+                paddr + size,
+                flags,
+                Size(size)
+            )
+        };
+
+        writeln!(f, "page table at {:#x} ({:?})", self.0.root, self.0.mode)?;
+        let mut run: Option<Run> = None;
+        let mut result = Ok(());
+        self.0.walk(|level, vaddr, entry| {
+            if !entry.is_leaf() || result.is_err() {
+                return;
+            }
+            let (vaddr, paddr) = (vaddr.as_usize(), entry.paddr().as_usize());
+            let (size, flags) = (self.0.mode.leaf_size(level), entry.pte_flags());
+            match &mut run {
+                Some(run) if run.0 + run.2 == vaddr && run.1 + run.2 == paddr && run.3 == flags => {
+                    run.2 += size;
+                }
+                _ => {
+                    if let Some(done) = run.replace((vaddr, paddr, size, flags)) {
+                        result = print(f, done);
+                    }
+                }
+            }
+        });
+        result?;
+        match run {
+            Some(run) => print(f, run),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Virtual start, physical start, size and flags of a run of pages.
+type Run = (usize, usize, usize, PteFlags);
+
+/// A byte count printed with the largest unit that divides it.
+struct Size(usize);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (value, unit) = [(30, 'G'), (20, 'M'), (10, 'K')]
+            .into_iter()
+            .find(|&(shift, _)| self.0.is_multiple_of(1 << shift))
+            .map_or((self.0, 'B'), |(shift, unit)| (self.0 >> shift, unit));
+        write!(f, "{}{}", value, unit)
+    }
+}
@@ -0,0 +1,97 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use conquer_once::spin::OnceCell;
+use memory_addr::PAGE_SIZE_4K;
+use riscv::register::satp::{self, Satp};
+use spin::Mutex;
+use wiheom_lib::asid::{Asid, AsidAllocator};
+
+use crate::stack::{self, MAX_HARTS};
+use crate::{println, sbi};
+
+/// Ranges of more pages are flushed for the whole address space at once.
+const FLUSH_ALL_PAGES: usize = 64;
+
+static ASIDS: OnceCell<Mutex<AsidAllocator<MAX_HARTS>>> = OnceCell::uninit();
+
+/// Bit mask of the harts that run the kernel and have to take part in shootdowns.
+static ONLINE_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Finds out how many ASIDs the harts support and marks the current hart online.
+///
+/// # Safety
+/// Paging has to be enabled, the ASID bits are probed by writing `satp`.
+pub unsafe fn init() {
+    let old = satp::read();
+    // Only the implemented ASID bits stick
+    unsafe { satp::write(Satp::from_bits(old.bits() | (0xffff << 44))) };
+    let max = satp::read().asid() as u16;
+    unsafe { satp::write(old) };
+    flush_all();
+    println!("ASIDs up to {}", max);
+
+    ASIDS.init_once(|| Mutex::new(AsidAllocator::new(max)));
+    ONLINE_HARTS.fetch_or(1 << stack::current_hart(), Ordering::Relaxed);
+}
+
+/// Returns the ASID to switch to for an address space that last ran with `current`.
+///
+/// Flushes the TLBs of all harts if the ASIDs rolled over, so this has to be called before the
+/// new ASID is written to `satp`.
+pub fn activate(current: Option<Asid>) -> Asid {
+    let (asid, flush) = ASIDS
+        .get()
+        .expect("ASIDs are not initialized")
+        .lock()
+        .activate(stack::current_hart(), current);
+    if flush {
+        shootdown(None, 0, usize::MAX);
+    }
+    asid
+}
+
+/// Flushes the TLB entries of the current hart for the page at `vaddr` in all address spaces.
+pub fn flush_page(vaddr: usize) {
+    unsafe { asm!("sfence.vma {}, zero", in(reg) vaddr, options(nostack)) };
+}
+
+/// Flushes the whole TLB of the current hart.
+pub fn flush_all() {
+    riscv::asm::sfence_vma_all();
+}
+
+/// Flushes the TLB entries of the current hart for the `size` bytes at `start`, of the address
+/// space `asid` or of all address spaces.
+pub fn flush_local(asid: Option<u16>, start: usize, size: usize) {
+    let pages = size.div_ceil(PAGE_SIZE_4K);
+    if pages > FLUSH_ALL_PAGES {
+        match asid {
+            Some(asid) => unsafe {
+                asm!("sfence.vma zero, {}", in(reg) asid as usize, options(nostack))
+            },
+            None => flush_all(),
+        }
+        return;
+    }
+    for page in 0..pages {
+        let vaddr = start + page * PAGE_SIZE_4K;
+        match asid {
+            Some(asid) => riscv::asm::sfence_vma(asid as usize, vaddr),
+            None => flush_page(vaddr),
+        }
+    }
+}
+
+/// Flushes the TLB entries for the `size` bytes at `start` on every online hart, the other
+/// harts are asked through the SBI.
+pub fn shootdown(asid: Option<u16>, start: usize, size: usize) {
+    flush_local(asid, start, size);
+    let others = ONLINE_HARTS.load(Ordering::Relaxed) & !(1 << stack::current_hart());
+    if others != 0 {
+        let ret = sbi::remote_sfence_vma(others, start, size, asid);
+        if ret.error != 0 {
+            println!("Remote TLB shootdown failed with error {}", ret.error);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use alloc::collections::BTreeMap;
+use memory_addr::PAGE_SIZE_4K;
+use page_table_multiarch::{MappingFlags, PagingError, PagingResult};
+
+/// What backs the pages of a [`Vma`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Zeroed frames, allocated on the first access.
+    Anonymous,
+    /// Physically contiguous memory starting at the given address, mapped up front.
+    Physical(usize),
+    /// Pages mapped by the owner of the area through a [`LeafTable`].
+    ///
+    /// [`LeafTable`]: super::page_table::LeafTable
+    External,
+}
+
+/// The kind of access that caused a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// A virtual memory area, a page aligned range of an address space with uniform flags.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    pub flags: MappingFlags,
+    pub backing: Backing,
+}
+
+impl Vma {
+    pub fn contains(&self, vaddr: usize) -> bool {
+        (self.start..self.end).contains(&vaddr)
+    }
+
+    /// Whether the flags of the area permit `access`, from user mode if `user` is set.
+    pub fn allows(&self, access: Access, user: bool) -> bool {
+        let needed = match access {
+            Access::Read => MappingFlags::READ,
+            Access::Write => MappingFlags::WRITE,
+            Access::Execute => MappingFlags::EXECUTE,
+        };
+        let needed = if user {
+            needed | MappingFlags::USER
+        } else {
+            needed
+        };
+        self.flags.contains(needed)
+    }
+}
+
+/// The non-overlapping areas of an address space, sorted by start address.
+pub struct VmaList {
+    areas: BTreeMap<usize, Vma>,
+}
+
+impl Default for VmaList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VmaList {
+    pub const fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    /// Adds `vma` to the list.
+    ///
+    /// Returns [`PagingError::NotAligned`] if the area is not page aligned and
+    /// [`PagingError::AlreadyMapped`] if it overlaps an existing area.
+    pub fn insert(&mut self, vma: Vma) -> PagingResult {
+        if !vma.start.is_multiple_of(PAGE_SIZE_4K)
+            || !vma.end.is_multiple_of(PAGE_SIZE_4K)
+            || vma.start >= vma.end
+        {
+            return Err(PagingError::NotAligned);
+        }
+        let overlaps = self
+            .areas
+            .range(..vma.end)
+            .next_back()
+            .is_some_and(|(_, prev)| prev.end > vma.start);
+        if overlaps {
+            return Err(PagingError::AlreadyMapped);
+        }
+        self.areas.insert(vma.start, vma);
+        Ok(())
+    }
+
+    /// Removes the area starting at `start`.
+    pub fn remove(&mut self, start: usize) -> Option<Vma> {
+        self.areas.remove(&start)
+    }
+
+    /// Splits the area containing `vaddr` in two at `vaddr`, which has to be page aligned.
+    ///
+    /// Nothing changes if `vaddr` is already the start of an area or outside of any area.
+    pub fn split(&mut self, vaddr: usize) {
+        let Some(vma) = self.find(vaddr).copied() else {
+            return;
+        };
+        if vma.start == vaddr {
+            return;
+        }
+        debug_assert!(vaddr.is_multiple_of(PAGE_SIZE_4K));
+        let backing = match vma.backing {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::Physical(paddr) => Backing::Physical(paddr + (vaddr - vma.start)),
+            Backing::External => Backing::External,
+        };
+        self.areas.get_mut(&vma.start).unwrap().end = vaddr;
+        self.areas.insert(
+            vaddr,
+            Vma {
+                start: vaddr,
+                backing,
+                ..vma
+            },
+        );
+    }
+
+    /// All areas, sorted by start address.
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+
+    /// The area starting at `start`.
+    pub fn get_mut(&mut self, start: usize) -> Option<&mut Vma> {
+        self.areas.get_mut(&start)
+    }
+
+    /// The area containing `vaddr`.
+    pub fn find(&self, vaddr: usize) -> Option<&Vma> {
+        self.areas
+            .range(..=vaddr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(vaddr))
+    }
+
+    /// The lowest start of `size` free bytes in `start..end` with at least `guard` free bytes
+    /// on both sides.
+    pub fn find_free(&self, start: usize, end: usize, size: usize, guard: usize) -> Option<usize> {
+        let mut candidate = start + guard;
+        for vma in self.areas.range(..end).map(|(_, vma)| vma) {
+            if vma.end <= start {
+                continue;
+            }
+            if candidate + size + guard <= vma.start {
+                return Some(candidate);
+            }
+            candidate = candidate.max(vma.end + guard);
+        }
+        (candidate + size + guard <= end).then_some(candidate)
+    }
+}
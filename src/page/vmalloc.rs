@@ -0,0 +1,67 @@
+use core::ptr::NonNull;
+
+use memory_addr::{PAGE_SIZE_4K, align_up_4k};
+use page_table_multiarch::{MappingFlags, PagingError, PagingResult};
+
+use super::vma::Backing;
+use super::{HEAP_GROW_SIZE, HEAP_GROW_START, kernel_space};
+use crate::println;
+
+/// Start of the virtual range of [`vmalloc`] allocations, right behind the heap range.
+pub const VMALLOC_START: usize = HEAP_GROW_START + HEAP_GROW_SIZE;
+
+/// Size of the virtual range of [`vmalloc`] allocations.
+pub const VMALLOC_SIZE: usize = 64 << 30;
+
+/// Unmapped bytes on both sides of every allocation, so overruns fault instead of reaching the
+/// next allocation.
+pub const VMALLOC_GUARD_SIZE: usize = PAGE_SIZE_4K;
+
+/// Allocates `size` bytes of virtually contiguous, zeroed kernel memory.
+///
+/// The memory is backed by single frames, so it does not need to be physically contiguous.
+/// Returns `None` if there is no virtual range or memory left.
+#[allow(dead_code)]
+pub fn vmalloc(size: usize) -> Option<NonNull<u8>> {
+    let size = align_up_4k(size.max(1));
+    let mut space = kernel_space().lock();
+    let start = space.areas().find_free(
+        VMALLOC_START,
+        VMALLOC_START + VMALLOC_SIZE,
+        size,
+        VMALLOC_GUARD_SIZE,
+    )?;
+    let result = space
+        .map(
+            start,
+            size,
+            MappingFlags::READ | MappingFlags::WRITE,
+            Backing::Anonymous,
+        )
+        .and_then(|()| {
+            space.populate(start, size).inspect_err(|_| {
+                // Frees the frames that were mapped already
+                space.unmap(start).unwrap();
+            })
+        });
+    match result {
+        Ok(()) => NonNull::new(start as *mut u8),
+        Err(e) => {
+            println!("vmalloc of {:#x} bytes failed: {:?}", size, e);
+            None
+        }
+    }
+}
+
+/// Unmaps an allocation of [`vmalloc`] and frees its frames.
+///
+/// # Safety
+/// `ptr` must have been returned by [`vmalloc`] and must not be used afterwards.
+#[allow(dead_code)]
+pub unsafe fn vfree(ptr: NonNull<u8>) -> PagingResult {
+    let start = ptr.as_ptr() as usize;
+    if !(VMALLOC_START..VMALLOC_START + VMALLOC_SIZE).contains(&start) {
+        return Err(PagingError::NotMapped);
+    }
+    kernel_space().lock().unmap(start)
+}
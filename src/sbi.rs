@@ -0,0 +1,49 @@
+use core::arch::asm;
+
+/// Remote fence extension.
+const EID_RFENCE: usize = 0x5246_4e43;
+
+/// System reset extension.
+const EID_SRST: usize = 0x5352_5354;
+
+/// The error code and value an SBI call returns.
+#[derive(Debug, Clone, Copy)]
+pub struct SbiRet {
+    pub error: isize,
+    #[allow(dead_code)]
+    pub value: usize,
+}
+
+/// Calls `function` of the SBI `extension` with `args`.
+fn call(extension: usize, function: usize, args: [usize; 5]) -> SbiRet {
+    let (error, value);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") args[0] => error,
+            inlateout("a1") args[1] => value,
+            in("a2") args[2],
+            in("a3") args[3],
+            in("a4") args[4],
+            in("a6") function,
+            in("a7") extension,
+        )
+    };
+    SbiRet { error, value }
+}
+
+/// Makes the harts in `hart_mask` flush their TLB entries for the `size` bytes at `start`, of
+/// the address space `asid` or of all address spaces.
+pub fn remote_sfence_vma(hart_mask: usize, start: usize, size: usize, asid: Option<u16>) -> SbiRet {
+    match asid {
+        Some(asid) => call(EID_RFENCE, 2, [hart_mask, 0, start, size, asid as usize]),
+        None => call(EID_RFENCE, 1, [hart_mask, 0, start, size, 0]),
+    }
+}
+
+/// Powers the machine off.
+pub fn shutdown() -> ! {
+    // Shutdown without a reason
+    let ret = call(EID_SRST, 0, [0; 5]);
+    panic!("SBI shutdown failed with error {}", ret.error);
+}
@@ -2,16 +2,22 @@ use uart_16550::MmioSerialPort;
 use spin::Mutex;
 use lazy_static::lazy_static;
 
-const SERIAL_PORT_BASE_ADDRESS: usize = 0x1000_0000;
+pub const SERIAL_PORT_BASE_ADDRESS: usize = 0x1000_0000;
 
 lazy_static! {
     pub static ref SERIAL1: Mutex<MmioSerialPort> = {
-        let mut serial_port = unsafe { MmioSerialPort::new(SERIAL_PORT_BASE_ADDRESS) };
+        let base = crate::page::phys_to_virt(SERIAL_PORT_BASE_ADDRESS);
+        let mut serial_port = unsafe { MmioSerialPort::new(base) };
         serial_port.init();
         Mutex::new(serial_port)
     };
 }
 
+/// Reads a byte from the serial port if one was received.
+pub fn try_receive() -> Option<u8> {
+    SERIAL1.lock().try_receive().ok()
+}
+
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
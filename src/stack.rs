@@ -0,0 +1,69 @@
+use core::arch::global_asm;
+
+use memory_addr::PAGE_SIZE_4K;
+
+use crate::page::addr;
+
+/// Number of harts the kernel reserves a stack for.
+pub const MAX_HARTS: usize = 2;
+
+/// Size of the stack slot of each hart, including the guard page at its bottom.
+///
+/// Has to be a power of two so the trap entry can find the offset of `sp` in its slot with shifts.
+pub const HART_STACK_SIZE: usize = 32 * 1024;
+
+/// Size of the unmapped guard page below every kernel stack.
+///
+/// Overflows are only caught if no function allocates a stack frame larger than this, a larger
+/// frame skips the guard page and runs into the stack of the next hart.
+pub const GUARD_SIZE: usize = PAGE_SIZE_4K;
+
+const _: () = assert!(HART_STACK_SIZE.is_power_of_two() && HART_STACK_SIZE > GUARD_SIZE);
+
+unsafe extern "C" {
+    static __estack: u8;
+    static __sstack: u8;
+}
+
+// Stack geometry used by riscv-rt to set up the stack of each hart
+global_asm!(
+    ".global _max_hart_id
+    .global _hart_stack_size
+    .set _max_hart_id, {max_hart_id}
+    .set _hart_stack_size, {hart_stack_size}",
+    max_hart_id = const MAX_HARTS - 1,
+    hart_stack_size = const HART_STACK_SIZE,
+);
+
+/// Bounds `(bottom, top)` of the usable stack of `hart`, without its guard page.
+pub fn hart_stack(hart: usize) -> (usize, usize) {
+    let top = unsafe { addr(&__sstack) } - hart * HART_STACK_SIZE;
+    (top - HART_STACK_SIZE + GUARD_SIZE, top)
+}
+
+/// The hart running this code, found from the stack it runs on.
+///
+/// Panics if it does not run on a hart stack, as the hart can't be told then.
+pub fn current_hart() -> usize {
+    let sp: usize;
+    unsafe { core::arch::asm!("mv {}, sp", out(reg) sp) };
+    let top = unsafe { addr(&__sstack) };
+    let hart = (top - 1)
+        .checked_sub(sp)
+        .map(|offset| offset / HART_STACK_SIZE);
+    match hart {
+        Some(hart) if hart < MAX_HARTS => hart,
+        _ => panic!("not running on a hart stack, sp is {:#x}", sp),
+    }
+}
+
+/// The hart whose guard page contains `vaddr`, if any.
+pub fn guard_page_hart(vaddr: usize) -> Option<usize> {
+    let (bottom, top) = unsafe { (addr(&__estack), addr(&__sstack)) };
+    if !(bottom..top).contains(&vaddr) {
+        return None;
+    }
+    let hart = (top - 1 - vaddr) / HART_STACK_SIZE;
+    let (stack_bottom, _) = hart_stack(hart);
+    (vaddr < stack_bottom).then_some(hart)
+}
@@ -0,0 +1,271 @@
+use core::arch::{asm, global_asm};
+use core::fmt;
+use core::mem::offset_of;
+
+use riscv::interrupt::{Exception, Interrupt, Trap};
+use riscv::register::scause::Scause;
+use riscv::register::sstatus::{self, SPP, Sstatus};
+
+use crate::stack::{self, GUARD_SIZE, HART_STACK_SIZE, MAX_HARTS};
+use crate::{exception, interrupt};
+
+/// Size of the stacks traps switch to when a kernel stack overflowed.
+///
+/// Has to be a power of two so the trap entry can find the stack of a hart with a shift.
+const EMERGENCY_STACK_SIZE: usize = 8 * 1024;
+
+const _: () = assert!(EMERGENCY_STACK_SIZE.is_power_of_two());
+
+/// Index of the stack pointer in [`TrapFrame::regs`].
+pub const SP: usize = 2;
+
+/// Index of the first argument and return value register in [`TrapFrame::regs`].
+pub const A0: usize = 10;
+
+/// ABI names of the general purpose registers.
+const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// The state of the interrupted code, saved by the trap entry and restored when the trap
+/// returns.
+///
+/// Handlers may change it to resume somewhere else, for example by moving `sepc` past the
+/// instruction that trapped.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct TrapFrame {
+    /// The general purpose registers, indexed by their number. `x0` is not saved.
+    pub regs: [usize; 32],
+    pub sepc: usize,
+    pub sstatus: usize,
+    pub stval: usize,
+    pub scause: usize,
+}
+
+const FRAME_SIZE: usize = size_of::<TrapFrame>();
+
+const _: () = assert!(FRAME_SIZE.is_multiple_of(16));
+
+impl TrapFrame {
+    pub fn cause(&self) -> Option<Trap<Interrupt, Exception>> {
+        Scause::from_bits(self.scause).cause().try_into().ok()
+    }
+
+    /// Whether the trap was taken from user mode.
+    pub fn is_user(&self) -> bool {
+        Sstatus::from_bits(self.sstatus).spp() == SPP::User
+    }
+
+    /// Moves `sepc` past the instruction that trapped, so it is not executed again.
+    pub fn skip_instruction(&mut self) {
+        // Compressed instructions are the ones whose lowest two bits are not both set
+        let parcel = if self.is_user() {
+            unsafe {
+                sstatus::set_sum();
+                let parcel = (self.sepc as *const u16).read_volatile();
+                sstatus::clear_sum();
+                parcel
+            }
+        } else {
+            unsafe { (self.sepc as *const u16).read_volatile() }
+        };
+        self.sepc += if parcel & 0b11 == 0b11 { 4 } else { 2 };
+    }
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "sepc {:#018x} sstatus {:#x} stval {:#x} scause {:#x}",
+            self.sepc, self.sstatus, self.stval, self.scause
+        )?;
+        for (index, (name, value)) in REG_NAMES.iter().zip(self.regs).enumerate().skip(1) {
+            write!(f, "{:>4} {:#018x}", name, value)?;
+            if index % 4 == 3 {
+                writeln!(f)?;
+            } else {
+                write!(f, " ")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[repr(C, align(16))]
+struct EmergencyStacks([[u8; EMERGENCY_STACK_SIZE]; MAX_HARTS]);
+
+static mut EMERGENCY_STACKS: EmergencyStacks =
+    EmergencyStacks([[0; EMERGENCY_STACK_SIZE]; MAX_HARTS]);
+
+// Trap entry and exit, replaces the default `_start_trap` of riscv-rt.
+//
+// While user code runs, `sscratch` holds the top of the kernel stack of the hart, in the kernel
+// it is 0. A trap from user mode swaps it with `sp`, pushes the frame on the empty kernel stack
+// and reloads `gp`, which user code is free to change.
+//
+// A trap from the kernel pushes the frame on the current stack. When a kernel stack overflows,
+// `sp` points into the guard page, or so close above it that pushing the frame would fault and
+// overwrite `sepc` and `scause` with a nested trap. In that case the trap is handled on the
+// emergency stack of the hart the stack belongs to. The original `sp` is lost, which does not
+// matter as the overflow is fatal. Stack frames larger than the guard page skip it and are not
+// detected, see `GUARD_SIZE`.
+//
+// `_trap_return` restores the frame `sp` points to and returns with `sret`. When it returns to
+// user mode, the kernel stack is empty again and its top goes to `sscratch`.
+global_asm!(
+    ".section .trap, \"ax\"
+    .align 4
+    .global _start_trap
+_start_trap:
+    csrrw sp, sscratch, sp
+    bnez sp, 2f
+
+    // From the kernel, sscratch is used to save t0 until the stack is known
+    csrrw sp, sscratch, sp
+    csrw sscratch, t0
+    la t0, __estack
+    bltu sp, t0, 1f
+    beq sp, t0, 5f
+    la t0, __sstack
+    bltu t0, sp, 1f
+
+    // Offset of sp in its stack slot, in (0, slot size]. It is taken from sp - 1, as sp at the
+    // top of a slot is the empty stack of its hart and not the bottom of the slot above. The
+    // guard page is at the bottom of the slot, the frame fits if the offset is at least the size
+    // of the guard page and the frame.
+    addi t0, sp, -1
+    slli t0, t0, 64 - {shift}
+    srli t0, t0, 64 - {shift}
+    addi t0, t0, 1 - {frame_size}
+    srai t0, t0, {guard_shift}
+    bgtz t0, 1f
+
+    // Hart of the slot, counted from the top of the stack region
+5:
+    la t0, __sstack
+    sub t0, t0, sp
+    addi t0, t0, -1
+    srli t0, t0, {shift}
+    addi t0, t0, 1
+    slli sp, t0, {emergency_shift}
+    la t0, {emergency_stacks}
+    add sp, sp, t0
+1:
+    csrrw t0, sscratch, zero
+    addi sp, sp, -{frame_size}
+    .irp reg, 1, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+    sd x\\reg, \\reg * 8(sp)
+    .endr
+    addi t0, sp, {frame_size}
+    sd t0, {sp_offset}(sp)
+    j 3f
+
+    // From user mode, sp is the top of the kernel stack and sscratch the user sp
+2:
+    addi sp, sp, -{frame_size}
+    .irp reg, 1, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+    sd x\\reg, \\reg * 8(sp)
+    .endr
+    csrrw t0, sscratch, zero
+    sd t0, {sp_offset}(sp)
+    .option push
+    .option norelax
+    la gp, __global_pointer$
+    .option pop
+
+3:
+    csrr t0, sepc
+    sd t0, {sepc}(sp)
+    csrr t0, sstatus
+    sd t0, {sstatus}(sp)
+    csrr t0, stval
+    sd t0, {stval}(sp)
+    csrr t0, scause
+    sd t0, {scause}(sp)
+    mv a0, sp
+    call {handler}
+
+    .global _trap_return
+_trap_return:
+    ld t0, {sstatus}(sp)
+    csrw sstatus, t0
+    andi t0, t0, {spp}
+    bnez t0, 4f
+    addi t0, sp, {frame_size}
+    csrw sscratch, t0
+4:
+    ld t0, {sepc}(sp)
+    csrw sepc, t0
+    .irp reg, 1, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+    ld x\\reg, \\reg * 8(sp)
+    .endr
+    ld sp, {sp_offset}(sp)
+    sret",
+    shift = const HART_STACK_SIZE.trailing_zeros(),
+    guard_shift = const GUARD_SIZE.trailing_zeros(),
+    emergency_shift = const EMERGENCY_STACK_SIZE.trailing_zeros(),
+    emergency_stacks = sym EMERGENCY_STACKS,
+    frame_size = const FRAME_SIZE,
+    sp_offset = const SP * 8,
+    sepc = const offset_of!(TrapFrame, sepc),
+    sstatus = const offset_of!(TrapFrame, sstatus),
+    stval = const offset_of!(TrapFrame, stval),
+    scause = const offset_of!(TrapFrame, scause),
+    spp = const 1 << 8,
+    handler = sym trap_handler,
+);
+
+/// Called by the trap entry with the frame of the interrupted code, which is restored when
+/// this returns.
+extern "C" fn trap_handler(frame: &mut TrapFrame) {
+    if let Some(hart) = emergency_stack_hart(frame) {
+        panic!("kernel stack overflow on hart {}\n{}", hart, frame);
+    }
+    match frame.cause() {
+        Some(Trap::Interrupt(interrupt)) => interrupt::handle_interrupt(interrupt),
+        Some(Trap::Exception(exception)) => exception::handle_exception(exception, frame),
+        None => panic!("Unknown trap cause {:#x}\n{}", frame.scause, frame),
+    }
+}
+
+/// The hart whose emergency stack holds `frame`, if the trap entry switched to one.
+fn emergency_stack_hart(frame: &TrapFrame) -> Option<usize> {
+    let base = (&raw const EMERGENCY_STACKS) as usize;
+    let offset = (frame as *const TrapFrame as usize).checked_sub(base)?;
+    let hart = offset / EMERGENCY_STACK_SIZE;
+    (hart < MAX_HARTS).then_some(hart)
+}
+
+/// Starts running user code at `entry` with the stack pointer `sp`.
+///
+/// The frame is placed at the top of the kernel stack of the hart, so everything running on it
+/// is given up, and the trap entry pushes the frames of user traps there again.
+///
+/// # Safety
+/// The current address space must map `entry` and the user stack for user mode.
+#[allow(dead_code)]
+pub unsafe fn enter_user(entry: usize, sp: usize) -> ! {
+    let mut status = sstatus::read();
+    status.set_spp(SPP::User);
+    // Interrupts stay disabled until the sret
+    status.set_sie(false);
+    status.set_spie(true);
+
+    let mut frame = TrapFrame {
+        sepc: entry,
+        sstatus: status.bits(),
+        ..Default::default()
+    };
+    frame.regs[SP] = sp;
+
+    let (_, top) = stack::hart_stack(stack::current_hart());
+    let slot = (top - FRAME_SIZE) as *mut TrapFrame;
+    unsafe {
+        slot.write(frame);
+        asm!("mv sp, {}", "j _trap_return", in(reg) slot, options(noreturn));
+    }
+}
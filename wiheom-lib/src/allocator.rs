@@ -0,0 +1,22 @@
+pub mod buddy;
+#[cfg(feature = "heap-debug")]
+pub mod debug;
+pub mod heap;
+pub mod slab;
+
+/// A wrapper around spin::Mutex to permit trait implementation.
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
+        self.inner.lock()
+    }
+}
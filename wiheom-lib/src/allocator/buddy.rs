@@ -0,0 +1,577 @@
+use core::alloc::Layout;
+use core::ptr;
+
+/// Size of the blocks of order 0.
+pub const PAGE: usize = 4096;
+/// Number of block orders, blocks of order `n` are `PAGE << n` bytes.
+pub const ORDERS: usize = 11;
+/// Size of the largest blocks.
+pub const MAX_BYTES: usize = PAGE << (ORDERS - 1);
+
+/// Maximum number of memory regions the allocator can manage.
+const MAX_REGIONS: usize = 16;
+
+/// Page state of the first page of a free block, or'ed with its order.
+const FREE: u8 = 0x80;
+/// Page state of the first page of an allocated block, or'ed with its order.
+const TAKEN: u8 = 0x40;
+
+/// Links of a free block, stored in the block itself.
+struct ListNode {
+    next: *mut ListNode,
+    prev: *mut ListNode,
+}
+
+/// A contiguous range of memory handed to the allocator.
+///
+/// The state of its pages is kept in a byte array at the start of the range, so a block's first
+/// bytes are never touched while it is allocated.
+#[derive(Clone, Copy)]
+struct Region {
+    start: usize,
+    end: usize,
+    states: *mut u8,
+}
+
+impl Region {
+    const EMPTY: Region = Region {
+        start: 0,
+        end: 0,
+        states: ptr::null_mut(),
+    };
+
+    /// Whether the whole block at `block` of `size` bytes lies in the region.
+    fn contains(&self, block: usize, size: usize) -> bool {
+        self.start <= block && block + size <= self.end
+    }
+
+    fn state(&self, block: usize) -> *mut u8 {
+        unsafe { self.states.add((block - self.start) / PAGE) }
+    }
+}
+
+/// A binary buddy allocator handing out page granular blocks.
+///
+/// Blocks are `PAGE << order` bytes and aligned to their size. A block is split in halves to
+/// serve smaller requests, and merged with its buddy, the other half, as soon as both are free.
+pub struct BuddyAllocator {
+    list_heads: [*mut ListNode; ORDERS],
+    regions: [Region; MAX_REGIONS],
+    region_count: usize,
+    free_pages: usize,
+    total_pages: usize,
+    free_blocks: [usize; ORDERS],
+}
+
+// The free lists only point into the memory the allocator owns
+unsafe impl Send for BuddyAllocator {}
+
+impl Default for BuddyAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BuddyAllocator {
+    /// Creates an empty BuddyAllocator
+    pub const fn new() -> Self {
+        BuddyAllocator {
+            list_heads: [ptr::null_mut(); ORDERS],
+            regions: [Region::EMPTY; MAX_REGIONS],
+            region_count: 0,
+            free_pages: 0,
+            total_pages: 0,
+            free_blocks: [0; ORDERS],
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// # Safety
+    /// This function is unsafe because the caller must guarantee that the given heap bounds are valid
+    /// and that the heap is unused. This method must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe { self.add_region(heap_start, heap_size) };
+    }
+
+    /// Adds the memory at `start` of `size` bytes to the allocator.
+    ///
+    /// The range is shrunk to whole pages, the first pages of it hold the page states and the
+    /// rest is cut into the largest blocks that are aligned to their size.
+    ///
+    /// # Safety
+    /// The memory must be valid, unused and not overlap memory the allocator already manages.
+    pub unsafe fn add_region(&mut self, start: usize, size: usize) {
+        let end = (start + size) & !(PAGE - 1);
+        let start = start.next_multiple_of(PAGE);
+        if start >= end {
+            return;
+        }
+        assert!(self.region_count < MAX_REGIONS, "too many heap regions");
+
+        let pages = (end - start) / PAGE;
+        let state_pages = pages.div_ceil(PAGE);
+        if state_pages >= pages {
+            return;
+        }
+        let region = Region {
+            start: start + state_pages * PAGE,
+            end,
+            states: start as *mut u8,
+        };
+        unsafe { region.states.write_bytes(0, pages - state_pages) };
+        self.regions[self.region_count] = region;
+        self.region_count += 1;
+
+        let mut block = region.start;
+        while block < end {
+            let order = (0..ORDERS)
+                .rev()
+                .find(|&order| {
+                    let size = block_size(order);
+                    block.is_multiple_of(size) && block + size <= end
+                })
+                .unwrap();
+            unsafe { self.push(&region, block, order) };
+            self.free_pages += 1 << order;
+            block += block_size(order);
+        }
+        self.total_pages += pages - state_pages;
+    }
+
+    /// Allocates a block for `layout`, aligned to at least its size.
+    ///
+    /// Returns null if the request is too large or the heap is exhausted.
+    pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        match order(layout) {
+            Some(order) => self.alloc_pages(order),
+            None => ptr::null_mut(),
+        }
+    }
+
+    /// Frees the block of `ptr`, which was returned by [`BuddyAllocator::alloc`] with `layout`.
+    ///
+    /// # Safety
+    /// `ptr` must have been allocated from this allocator with the same layout and not freed yet.
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let order = order(layout).expect("freeing a block larger than any block");
+        unsafe { self.dealloc_pages(ptr, order) };
+    }
+
+    /// Allocates a block of `PAGE << order` bytes, splitting a larger one if needed.
+    ///
+    /// Returns null if there is no free block of at least `order`.
+    pub fn alloc_pages(&mut self, order: usize) -> *mut u8 {
+        let Some(found) = (order..ORDERS).find(|&current| !self.list_heads[current].is_null())
+        else {
+            return ptr::null_mut();
+        };
+        let block = self.list_heads[found] as usize;
+        let region = self.region(block);
+        unsafe {
+            self.remove(&region, block, found);
+            // Keep the lower half and give the upper halves back
+            for current in (order..found).rev() {
+                self.push(&region, block + block_size(current), current);
+            }
+            *region.state(block) = TAKEN | order as u8;
+        }
+        self.free_pages -= 1 << order;
+        block as *mut u8
+    }
+
+    /// Frees a block returned by [`BuddyAllocator::alloc_pages`] with the same `order`.
+    ///
+    /// Panics if the block is not allocated, which catches double frees.
+    ///
+    /// # Safety
+    /// `ptr` must not be used after it is freed.
+    pub unsafe fn dealloc_pages(&mut self, ptr: *mut u8, order: usize) {
+        let mut block = ptr as usize;
+        let region = self.region(block);
+        assert!(
+            unsafe { *region.state(block) } == TAKEN | order as u8,
+            "freeing block {:#x} of order {} that is not allocated",
+            block,
+            order
+        );
+        self.free_pages += 1 << order;
+
+        let mut order = order;
+        while order + 1 < ORDERS {
+            let buddy = block ^ block_size(order);
+            if !region.contains(buddy, block_size(order))
+                || unsafe { *region.state(buddy) } != FREE | order as u8
+            {
+                break;
+            }
+            unsafe {
+                self.remove(&region, buddy, order);
+                *region.state(block) = 0;
+                *region.state(buddy) = 0;
+            }
+            block = block.min(buddy);
+            order += 1;
+        }
+        unsafe { self.push(&region, block, order) };
+    }
+
+    /// Whether [`BuddyAllocator::add_region`] would fail because there are too many regions.
+    pub fn regions_full(&self) -> bool {
+        self.region_count == MAX_REGIONS
+    }
+
+    /// Number of free pages, in blocks of any order.
+    pub fn free_pages(&self) -> usize {
+        self.free_pages
+    }
+
+    /// Number of pages that can be allocated, without the pages holding the page states.
+    pub fn total_pages(&self) -> usize {
+        self.total_pages
+    }
+
+    /// Number of free blocks of every order.
+    pub fn free_blocks(&self) -> [usize; ORDERS] {
+        self.free_blocks
+    }
+
+    /// The region containing `block`.
+    fn region(&self, block: usize) -> Region {
+        *self.regions[..self.region_count]
+            .iter()
+            .find(|region| region.contains(block, PAGE))
+            .unwrap_or_else(|| panic!("block {:#x} is outside of the heap", block))
+    }
+
+    /// Puts the block at `block` at the head of the free list of `order`.
+    unsafe fn push(&mut self, region: &Region, block: usize, order: usize) {
+        let node = block as *mut ListNode;
+        let head = self.list_heads[order];
+        unsafe {
+            node.write(ListNode {
+                next: head,
+                prev: ptr::null_mut(),
+            });
+            if !head.is_null() {
+                (*head).prev = node;
+            }
+            *region.state(block) = FREE | order as u8;
+        }
+        self.list_heads[order] = node;
+        self.free_blocks[order] += 1;
+    }
+
+    /// Unlinks the free block at `block` from the free list of `order`.
+    unsafe fn remove(&mut self, region: &Region, block: usize, order: usize) {
+        let node = block as *mut ListNode;
+        unsafe {
+            let ListNode { next, prev } = node.read();
+            if prev.is_null() {
+                self.list_heads[order] = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+            *region.state(block) = 0;
+        }
+        self.free_blocks[order] -= 1;
+    }
+}
+
+fn block_size(order: usize) -> usize {
+    PAGE << order
+}
+
+/// The smallest order whose blocks fit `layout`.
+pub fn order(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    if size > MAX_BYTES {
+        return None;
+    }
+    Some(
+        size.max(PAGE).next_power_of_two().trailing_zeros() as usize
+            - PAGE.trailing_zeros() as usize,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{Rng, aligned_memory};
+    use std::collections::BTreeMap;
+
+    const ORDER_MASK: u8 = 0x3f;
+
+    fn allocator(start: usize, size: usize) -> BuddyAllocator {
+        let mut allocator = BuddyAllocator::new();
+        unsafe { allocator.init(start, size) };
+        allocator
+    }
+
+    fn free_blocks(allocator: &BuddyAllocator, order: usize) -> Vec<usize> {
+        let mut blocks = Vec::new();
+        let mut node = allocator.list_heads[order];
+        while !node.is_null() {
+            blocks.push(node as usize);
+            node = unsafe { (*node).next };
+        }
+        blocks
+    }
+
+    /// Checks the page states against the free lists and the blocks the test holds.
+    ///
+    /// `taken` maps the start of every allocated block to its order.
+    fn check_invariants(allocator: &BuddyAllocator, taken: &BTreeMap<usize, usize>) {
+        let mut listed = BTreeMap::new();
+        for order in 0..ORDERS {
+            assert_eq!(
+                allocator.free_blocks()[order],
+                free_blocks(allocator, order).len(),
+                "wrong free block count of order {}",
+                order
+            );
+            let mut prev = ptr::null_mut();
+            let mut node = allocator.list_heads[order];
+            while !node.is_null() {
+                let links = unsafe { &*node };
+                assert_eq!(links.prev, prev, "broken back link at {:#x}", node as usize);
+                assert!(
+                    listed.insert(node as usize, order).is_none(),
+                    "block {:#x} is listed twice",
+                    node as usize
+                );
+                prev = node;
+                node = links.next;
+            }
+        }
+
+        // The blocks have to tile every region
+        let mut free_pages = 0;
+        for region in &allocator.regions[..allocator.region_count] {
+            let mut block = region.start;
+            while block < region.end {
+                let state = unsafe { *region.state(block) };
+                let order = (state & ORDER_MASK) as usize;
+                let size = block_size(order);
+                assert!(
+                    block.is_multiple_of(size),
+                    "block {:#x} is misaligned",
+                    block
+                );
+                if state & FREE != 0 {
+                    assert_eq!(listed.remove(&block), Some(order));
+                    free_pages += 1 << order;
+                    let buddy = block ^ size;
+                    if order + 1 < ORDERS && region.contains(buddy, size) {
+                        assert_ne!(
+                            unsafe { *region.state(buddy) },
+                            FREE | order as u8,
+                            "free buddies {:#x} and {:#x} were not merged",
+                            block,
+                            buddy
+                        );
+                    }
+                } else {
+                    assert_eq!(state & TAKEN, TAKEN, "no block starts at {:#x}", block);
+                    assert_eq!(taken.get(&block), Some(&order));
+                }
+                block += size;
+            }
+            assert_eq!(block, region.end);
+        }
+        assert!(listed.is_empty(), "free list entries outside of the heap");
+        assert_eq!(allocator.free_pages(), free_pages);
+    }
+
+    #[test]
+    fn init_uses_largest_blocks() {
+        let start = aligned_memory(5 * MAX_BYTES, MAX_BYTES);
+        // The page states take the first two pages, so the first top level block is lost
+        let allocator = allocator(start, 5 * MAX_BYTES);
+        assert_eq!(free_blocks(&allocator, ORDERS - 1).len(), 4);
+        assert_eq!(allocator.free_pages(), 5 * MAX_BYTES / PAGE - 2);
+        check_invariants(&allocator, &BTreeMap::new());
+    }
+
+    #[test]
+    fn init_unaligned_heap() {
+        let memory = aligned_memory(4 * MAX_BYTES, MAX_BYTES);
+        let (start, size) = (memory + 3 * PAGE + 5, 3 * MAX_BYTES - 100);
+        let allocator = allocator(start, size);
+        let region = allocator.regions[0];
+        assert!(region.states as usize >= start && region.end <= start + size);
+        assert_eq!(allocator.free_pages(), (region.end - region.start) / PAGE);
+        check_invariants(&allocator, &BTreeMap::new());
+    }
+
+    #[test]
+    fn split_and_merge() {
+        let memory = aligned_memory(2 * MAX_BYTES, MAX_BYTES);
+        let mut allocator = allocator(memory + MAX_BYTES - PAGE, MAX_BYTES + PAGE);
+        let top = memory + MAX_BYTES;
+        assert_eq!(free_blocks(&allocator, ORDERS - 1), [top]);
+
+        let first = allocator.alloc_pages(0);
+        for order in 0..ORDERS - 1 {
+            assert_eq!(free_blocks(&allocator, order).len(), 1);
+        }
+        assert!(free_blocks(&allocator, ORDERS - 1).is_empty());
+
+        let second = allocator.alloc_pages(0);
+        assert_eq!(second as usize - first as usize, PAGE);
+        assert!(free_blocks(&allocator, 0).is_empty());
+
+        unsafe {
+            allocator.dealloc_pages(first, 0);
+            allocator.dealloc_pages(second, 0);
+        }
+        assert_eq!(free_blocks(&allocator, ORDERS - 1), [top]);
+        check_invariants(&allocator, &BTreeMap::new());
+    }
+
+    #[test]
+    fn exhaustion_returns_null() {
+        let memory = aligned_memory(MAX_BYTES, PAGE);
+        let mut allocator = allocator(memory, 17 * PAGE);
+        let blocks: Vec<_> = core::iter::from_fn(|| Some(allocator.alloc_pages(0)))
+            .take_while(|ptr| !ptr.is_null())
+            .collect();
+        assert_eq!(blocks.len(), 16);
+        assert!(allocator.alloc_pages(0).is_null());
+
+        for ptr in blocks {
+            unsafe { allocator.dealloc_pages(ptr, 0) };
+        }
+        assert_eq!(allocator.free_pages(), 16);
+    }
+
+    #[test]
+    fn too_large_returns_null() {
+        let memory = aligned_memory(2 * MAX_BYTES, MAX_BYTES);
+        let mut allocator = allocator(memory, 2 * MAX_BYTES);
+        let free = allocator.free_pages();
+        let layout = Layout::from_size_align(MAX_BYTES + 1, 8).unwrap();
+        assert!(allocator.alloc(layout).is_null());
+        assert_eq!(allocator.free_pages(), free);
+    }
+
+    #[test]
+    fn alignment_is_honored() {
+        let memory = aligned_memory(4 * MAX_BYTES, PAGE);
+        let mut allocator = allocator(memory, 4 * MAX_BYTES);
+        let free = allocator.free_pages();
+        for align in (0..16).map(|shift| 1 << shift) {
+            let layout = Layout::from_size_align(24, align).unwrap();
+            let ptr = allocator.alloc(layout);
+            assert!(!ptr.is_null());
+            assert!((ptr as usize).is_multiple_of(align));
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+        assert_eq!(allocator.free_pages(), free);
+    }
+
+    #[test]
+    fn layouts_map_to_orders() {
+        let layout = |size, align| Layout::from_size_align(size, align).unwrap();
+        assert_eq!(order(layout(1, 1)), Some(0));
+        assert_eq!(order(layout(PAGE + 1, 8)), Some(1));
+        assert_eq!(order(layout(8, 4 * PAGE)), Some(2));
+        assert_eq!(order(layout(MAX_BYTES, 8)), Some(ORDERS - 1));
+        assert_eq!(order(layout(MAX_BYTES + 1, 8)), None);
+    }
+
+    #[test]
+    fn blocks_are_aligned_to_their_size() {
+        let memory = aligned_memory(4 * MAX_BYTES, PAGE);
+        let mut allocator = allocator(memory, 4 * MAX_BYTES);
+        for order in 0..ORDERS {
+            let ptr = allocator.alloc_pages(order);
+            assert!(!ptr.is_null());
+            assert!((ptr as usize).is_multiple_of(block_size(order)));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "not allocated")]
+    fn double_free_panics() {
+        let memory = aligned_memory(MAX_BYTES, PAGE);
+        let mut allocator = allocator(memory, MAX_BYTES);
+        let ptr = allocator.alloc_pages(1);
+        unsafe {
+            allocator.dealloc_pages(ptr, 1);
+            allocator.dealloc_pages(ptr, 1);
+        }
+    }
+
+    #[test]
+    fn regions_do_not_merge() {
+        let memory = aligned_memory(2 * MAX_BYTES, MAX_BYTES);
+        let mut allocator = BuddyAllocator::new();
+        unsafe {
+            allocator.add_region(memory, MAX_BYTES / 2);
+            allocator.add_region(memory + MAX_BYTES / 2, MAX_BYTES / 2);
+        }
+        let mut taken = BTreeMap::new();
+        let blocks: Vec<_> = core::iter::from_fn(|| Some(allocator.alloc_pages(0)))
+            .take_while(|ptr| !ptr.is_null())
+            .collect();
+        for &block in &blocks {
+            taken.insert(block as usize, 0);
+        }
+        check_invariants(&allocator, &taken);
+        for block in blocks {
+            unsafe { allocator.dealloc_pages(block, 0) };
+        }
+        check_invariants(&allocator, &BTreeMap::new());
+        assert!(free_blocks(&allocator, ORDERS - 1).is_empty());
+    }
+
+    #[test]
+    fn random_alloc_and_free() {
+        const HEAP_SIZE: usize = 8 * MAX_BYTES;
+        let memory = aligned_memory(HEAP_SIZE, MAX_BYTES);
+        // An odd start and size so the heap doesn't tile into whole top level blocks
+        let mut allocator = allocator(memory + PAGE, HEAP_SIZE - 3 * PAGE);
+        let initial = allocator.free_pages();
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+
+        // Live allocations with the byte their memory is filled with
+        let mut live: Vec<(*mut u8, usize, u8)> = Vec::new();
+        let mut taken = BTreeMap::new();
+        for round in 0..20_000 {
+            if live.is_empty() || rng.below(100) < 55 {
+                let max_order = rng.below(ORDERS);
+                let order = rng.below(max_order + 1);
+                let ptr = allocator.alloc_pages(order);
+                if ptr.is_null() {
+                    continue;
+                }
+                let fill = round as u8;
+                unsafe { ptr.write_bytes(fill, block_size(order)) };
+                assert!(taken.insert(ptr as usize, order).is_none());
+                live.push((ptr, order, fill));
+            } else {
+                let (ptr, order, fill) = live.swap_remove(rng.below(live.len()));
+                let memory = unsafe { core::slice::from_raw_parts(ptr, block_size(order)) };
+                assert!(
+                    memory.iter().all(|&byte| byte == fill),
+                    "memory was clobbered"
+                );
+                taken.remove(&(ptr as usize));
+                unsafe { allocator.dealloc_pages(ptr, order) };
+            }
+            if round % 500 == 0 {
+                check_invariants(&allocator, &taken);
+            }
+        }
+
+        for (ptr, order, _) in live.drain(..) {
+            unsafe { allocator.dealloc_pages(ptr, order) };
+        }
+        check_invariants(&allocator, &BTreeMap::new());
+        assert_eq!(allocator.free_pages(), initial);
+    }
+}
@@ -0,0 +1,259 @@
+//! Heap debugging, enabled with the `heap-debug` feature.
+//!
+//! Every object is placed in a larger block together with a header and red zones:
+//!
+//! ```text
+//! | link | Header | red zone | object | red zone |
+//! ```
+//!
+//! The link bytes are left to the allocators for their free lists. The red zones are checked
+//! when the object is freed, and the freed block is filled with poison that is checked when the
+//! memory is handed out again. The header records the allocation site, so every report can tell
+//! where the object came from.
+
+use core::alloc::Layout;
+use core::fmt;
+
+use super::buddy::PAGE;
+
+/// Number of return addresses recorded for an allocation.
+pub const SITE_DEPTH: usize = 8;
+
+/// Where an object was allocated, the innermost return addresses at the time of the allocation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocSite(pub [usize; SITE_DEPTH]);
+
+impl fmt::Display for AllocSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut frames = self.0.iter().take_while(|&&ra| ra != 0);
+        match frames.next() {
+            Some(first) => write!(f, "{:#x}", first)?,
+            None => return write!(f, "<unknown>"),
+        }
+        for ra in frames {
+            write!(f, " <- {:#x}", ra)?;
+        }
+        Ok(())
+    }
+}
+
+/// Captures the [`AllocSite`] of an allocation, called with the heap locked.
+pub type SiteFn = fn() -> AllocSite;
+
+/// Byte pattern of freed memory.
+pub const POISON: u8 = 0x6b;
+
+/// Byte pattern of the red zones.
+const RED: u8 = 0xfd;
+
+/// Minimal size of each red zone.
+const RED_ZONE: usize = 16;
+
+/// Bytes at the start of a block the allocators may use for their free lists.
+const LINK: usize = 2 * size_of::<usize>();
+
+/// Bytes at the start of a block and of every page that are not poisoned, as they may hold
+/// the links of the allocators or the header of an earlier block.
+const HEADER_END: usize = LINK + size_of::<Header>();
+
+const LIVE: u64 = 0x4556_494c_4556_494c;
+const FREED: u64 = 0x4545_5246_4545_5246;
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    size: usize,
+    site: AllocSite,
+}
+
+/// Offset of an object of `layout` in its block.
+fn front(layout: Layout) -> usize {
+    (HEADER_END + RED_ZONE).next_multiple_of(layout.align())
+}
+
+/// Layout of the block holding an object of `layout` with its header and red zones.
+pub fn padded(layout: Layout) -> Option<Layout> {
+    let size = front(layout)
+        .checked_add(layout.size())?
+        .checked_add(RED_ZONE)?;
+    Layout::from_size_align(size, layout.align().max(align_of::<Header>())).ok()
+}
+
+/// Fills `len` bytes at `start` with [`POISON`].
+///
+/// # Safety
+/// The memory must be unused.
+pub unsafe fn poison(start: *mut u8, len: usize) {
+    unsafe { start.write_bytes(POISON, len) };
+}
+
+/// Panics if free memory in the `len` bytes at `block` is not poisoned anymore.
+///
+/// # Safety
+/// The memory must be readable.
+pub unsafe fn check_poison(block: *mut u8, len: usize) {
+    for offset in HEADER_END..len {
+        if (block as usize + offset) % PAGE < HEADER_END {
+            continue;
+        }
+        if unsafe { block.add(offset).read() } == POISON {
+            continue;
+        }
+        let header = unsafe { &*header(block) };
+        let site = if header.magic == FREED {
+            header.site
+        } else {
+            AllocSite::default()
+        };
+        panic!(
+            "use after free: {:p} was written while it was free, block allocated at {}",
+            unsafe { block.add(offset) },
+            site
+        );
+    }
+}
+
+/// Turns `block`, allocated with the [`padded`] `layout`, into an object with red zones, and
+/// returns the object.
+///
+/// Panics if the block was written to while it was free.
+///
+/// # Safety
+/// `block` must be a newly allocated block of the padded `layout`.
+pub unsafe fn guard(block: *mut u8, layout: Layout, site: AllocSite) -> *mut u8 {
+    let front = front(layout);
+    unsafe {
+        check_poison(block, front + layout.size() + RED_ZONE);
+        header(block).write(Header {
+            magic: LIVE,
+            size: layout.size(),
+            site,
+        });
+        block.add(HEADER_END).write_bytes(RED, front - HEADER_END);
+        block.add(front + layout.size()).write_bytes(RED, RED_ZONE);
+        block.add(front)
+    }
+}
+
+/// Checks the header and red zones of `object`, poisons it and returns its block.
+///
+/// Panics on a double free or an overwritten red zone.
+///
+/// # Safety
+/// `object` must have been returned by [`guard`] with `layout`.
+pub unsafe fn unguard(object: *mut u8, layout: Layout) -> *mut u8 {
+    let front = front(layout);
+    let block = unsafe { object.sub(front) };
+    let header = unsafe { &mut *header(block) };
+    match header.magic {
+        LIVE => {}
+        FREED => panic!("double free of {:p}, allocated at {}", object, header.site),
+        _ => panic!(
+            "freeing {:p}, which is not allocated or has a corrupted header",
+            object
+        ),
+    }
+    assert_eq!(
+        header.size,
+        layout.size(),
+        "freeing {:p} with the wrong size, allocated at {}",
+        object,
+        header.site
+    );
+
+    let before = unsafe { block.add(HEADER_END) };
+    let after = unsafe { object.add(layout.size()) };
+    for (zone, len) in [(before, front - HEADER_END), (after, RED_ZONE)] {
+        let zone = unsafe { core::slice::from_raw_parts(zone, len) };
+        if let Some(offset) = zone.iter().position(|&byte| byte != RED) {
+            panic!(
+                "red zone overwritten at {:p} ({} bytes object at {:p}), allocated at {}",
+                &zone[offset],
+                layout.size(),
+                object,
+                header.site
+            );
+        }
+    }
+
+    header.magic = FREED;
+    unsafe { poison(before, front + layout.size() + RED_ZONE - HEADER_END) };
+    block
+}
+
+fn header(block: *mut u8) -> *mut Header {
+    unsafe { block.add(LINK) as *mut Header }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::aligned_memory;
+
+    fn block(layout: Layout) -> *mut u8 {
+        let padded = padded(layout).unwrap();
+        let block = aligned_memory(padded.size(), padded.align()) as *mut u8;
+        unsafe { poison(block, padded.size()) };
+        block
+    }
+
+    #[test]
+    fn objects_are_aligned() {
+        for align in [1, 8, 64, 4096] {
+            let layout = Layout::from_size_align(24, align).unwrap();
+            let object = unsafe { guard(block(layout), layout, AllocSite::default()) };
+            assert!((object as usize).is_multiple_of(align));
+            unsafe { unguard(object, layout) };
+        }
+    }
+
+    #[test]
+    fn site_is_displayed() {
+        let mut site = AllocSite::default();
+        assert_eq!(site.to_string(), "<unknown>");
+        site.0[..2].copy_from_slice(&[0x1000, 0x2000]);
+        assert_eq!(site.to_string(), "0x1000 <- 0x2000");
+    }
+
+    #[test]
+    #[should_panic(expected = "red zone overwritten")]
+    fn overflow_is_reported() {
+        let layout = Layout::new::<[u8; 10]>();
+        let object = unsafe { guard(block(layout), layout, AllocSite::default()) };
+        unsafe { object.add(10).write(0) };
+        unsafe { unguard(object, layout) };
+    }
+
+    #[test]
+    #[should_panic(expected = "red zone overwritten")]
+    fn underflow_is_reported() {
+        let layout = Layout::new::<u64>();
+        let object = unsafe { guard(block(layout), layout, AllocSite::default()) };
+        unsafe { object.sub(1).write(0) };
+        unsafe { unguard(object, layout) };
+    }
+
+    #[test]
+    #[should_panic(expected = "double free of")]
+    fn double_free_is_reported() {
+        let layout = Layout::new::<u64>();
+        let object = unsafe { guard(block(layout), layout, AllocSite::default()) };
+        unsafe { unguard(object, layout) };
+        unsafe { unguard(object, layout) };
+    }
+
+    #[test]
+    #[should_panic(expected = "allocated at 0x1234")]
+    fn use_after_free_reports_the_site() {
+        let layout = Layout::new::<u64>();
+        let block = block(layout);
+        let mut site = AllocSite::default();
+        site.0[0] = 0x1234;
+        let object = unsafe { guard(block, layout, site) };
+        unsafe {
+            unguard(object, layout);
+            object.write(1);
+            guard(block, layout, AllocSite::default());
+        }
+    }
+}
@@ -0,0 +1,595 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::{fmt, ptr};
+
+use super::Locked;
+use super::buddy::{self, BuddyAllocator, ORDERS, PAGE};
+#[cfg(feature = "heap-debug")]
+use super::debug::{self, AllocSite, SiteFn};
+use super::slab::{PageAllocator, SlabCache};
+
+/// The object sizes of the slab caches of the heap.
+///
+/// The sizes are powers of two, so objects are aligned to their size. Larger requests are served
+/// by the buddy allocator directly.
+const SIZE_CLASSES: [usize; 8] = [8, 16, 32, 64, 128, 256, 512, 1024];
+
+/// Called when the heap runs out of memory, with the number of bytes it needs at least.
+///
+/// Returns the start and size of unused memory to add to the heap, or `None` if there is none.
+pub type GrowFn = fn(usize) -> Option<(usize, usize)>;
+
+/// Usage of one slab cache of the heap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClassStats {
+    pub object_size: usize,
+    /// Number of slab pages.
+    pub slabs: usize,
+    /// Number of objects in use.
+    pub in_use: usize,
+    /// Number of objects in a slab.
+    pub capacity: usize,
+}
+
+/// A snapshot of the counters of a [`Heap`].
+///
+/// Byte counts are the sizes that were requested, pages handed to [`PageAllocator`] users count
+/// as a page each.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes allocated since the heap was created.
+    pub allocated: usize,
+    /// Bytes freed since the heap was created.
+    pub freed: usize,
+    /// Most bytes that were in use at once.
+    pub peak: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    /// Allocations that returned null.
+    pub failed: usize,
+    /// Pages managed by the buddy allocator.
+    pub total_pages: usize,
+    pub free_pages: usize,
+    /// Free buddy blocks of every order.
+    pub free_blocks: [usize; ORDERS],
+    pub classes: [ClassStats; SIZE_CLASSES.len()],
+}
+
+impl HeapStats {
+    /// Bytes currently in use.
+    pub fn in_use(&self) -> usize {
+        self.allocated - self.freed
+    }
+
+    fn count_alloc(&mut self, size: usize) {
+        self.allocated += size;
+        self.allocations += 1;
+        self.peak = self.peak.max(self.in_use());
+    }
+
+    fn count_dealloc(&mut self, size: usize) {
+        self.freed += size;
+        self.frees += 1;
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "heap: {} bytes in use, peak {}, {} allocated, {} freed",
+            self.in_use(),
+            self.peak,
+            self.allocated,
+            self.freed
+        )?;
+        writeln!(
+            f,
+            "  {} allocations, {} frees, {} failed",
+            self.allocations, self.frees, self.failed
+        )?;
+        writeln!(
+            f,
+            "  buddy: {} of {} pages free",
+            self.free_pages, self.total_pages
+        )?;
+        for (order, count) in self.free_blocks.iter().enumerate() {
+            writeln!(f, "    order {:2}: {} free blocks", order, count)?;
+        }
+        writeln!(f, "  slabs:")?;
+        for class in &self.classes {
+            writeln!(
+                f,
+                "    {:4} bytes: {} objects in use, {} slabs of {}",
+                class.object_size, class.in_use, class.slabs, class.capacity
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The kernel heap: slab caches for small objects on top of a page granular buddy allocator.
+///
+/// With the `heap-debug` feature, objects get red zones and freed memory is poisoned, see
+/// the `debug` module.
+pub struct Heap {
+    buddy: BuddyAllocator,
+    caches: [SlabCache; SIZE_CLASSES.len()],
+    grow: Option<GrowFn>,
+    #[cfg(feature = "heap-debug")]
+    site: SiteFn,
+    counters: HeapStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub const fn new() -> Self {
+        Self::with_grow_fn(None)
+    }
+
+    /// Creates a heap that asks `grow` for more memory when it is exhausted.
+    pub const fn with_grow(grow: GrowFn) -> Self {
+        Self::with_grow_fn(Some(grow))
+    }
+
+    const fn with_grow_fn(grow: Option<GrowFn>) -> Self {
+        let mut caches = [const { SlabCache::new(0, 1) }; SIZE_CLASSES.len()];
+        let mut class = 0;
+        while class < SIZE_CLASSES.len() {
+            caches[class] = SlabCache::new(SIZE_CLASSES[class], SIZE_CLASSES[class]);
+            class += 1;
+        }
+        Self {
+            buddy: BuddyAllocator::new(),
+            caches,
+            grow,
+            #[cfg(feature = "heap-debug")]
+            site: AllocSite::default,
+            counters: HeapStats {
+                allocated: 0,
+                freed: 0,
+                peak: 0,
+                allocations: 0,
+                frees: 0,
+                failed: 0,
+                total_pages: 0,
+                free_pages: 0,
+                free_blocks: [0; ORDERS],
+                classes: [ClassStats {
+                    object_size: 0,
+                    slabs: 0,
+                    in_use: 0,
+                    capacity: 0,
+                }; SIZE_CLASSES.len()],
+            },
+        }
+    }
+
+    /// Records the allocation site of every object with `site`, for the reports of heap errors.
+    #[cfg(feature = "heap-debug")]
+    pub const fn with_site(mut self, site: SiteFn) -> Self {
+        self.site = site;
+        self
+    }
+
+    /// Initialize the heap with the given bounds.
+    ///
+    /// # Safety
+    /// The caller must guarantee that the given heap bounds are valid and that the heap is
+    /// unused. This method must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        #[cfg(feature = "heap-debug")]
+        unsafe {
+            debug::poison(heap_start as *mut u8, heap_size)
+        };
+        unsafe { self.buddy.init(heap_start, heap_size) };
+    }
+
+    /// A snapshot of the counters of the heap.
+    pub fn stats(&self) -> HeapStats {
+        let mut stats = self.counters;
+        stats.total_pages = self.buddy.total_pages();
+        stats.free_pages = self.buddy.free_pages();
+        stats.free_blocks = self.buddy.free_blocks();
+        for (class, cache) in stats.classes.iter_mut().zip(&self.caches) {
+            *class = ClassStats {
+                object_size: cache.object_size(),
+                slabs: cache.slabs(),
+                in_use: cache.in_use(),
+                capacity: cache.capacity(),
+            };
+        }
+        stats
+    }
+
+    /// Allocates memory for `layout`, returns null if there is none left.
+    ///
+    /// Any alignment up to [`MAX_BYTES`](buddy::MAX_BYTES) is honored, so page aligned structs
+    /// and buffers can come from the heap.
+    pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let ptr = self.alloc_object(layout);
+        if ptr.is_null() {
+            self.counters.failed += 1;
+        } else {
+            self.counters.count_alloc(layout.size());
+        }
+        ptr
+    }
+
+    /// Frees memory returned by [`Heap::alloc`] with the same `layout`.
+    ///
+    /// # Safety
+    /// `ptr` must have been allocated from this heap with `layout` and not freed yet.
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.counters.count_dealloc(layout.size());
+        unsafe { self.dealloc_object(ptr, layout) }
+    }
+
+    #[cfg(not(feature = "heap-debug"))]
+    fn alloc_object(&mut self, layout: Layout) -> *mut u8 {
+        self.alloc_block(layout)
+    }
+
+    #[cfg(not(feature = "heap-debug"))]
+    unsafe fn dealloc_object(&mut self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.dealloc_block(ptr, layout) }
+    }
+
+    /// Allocates memory for `layout` with red zones around it.
+    ///
+    /// Objects whose red zones do not fit into the largest block, like those aligned to it, only
+    /// get their memory poisoned.
+    #[cfg(feature = "heap-debug")]
+    fn alloc_object(&mut self, layout: Layout) -> *mut u8 {
+        let Some(padded) = guarded(layout) else {
+            let block = self.alloc_block(layout);
+            if !block.is_null() {
+                unsafe { debug::check_poison(block, layout.size()) };
+            }
+            return block;
+        };
+        let block = self.alloc_block(padded);
+        if block.is_null() {
+            return block;
+        }
+        unsafe { debug::guard(block, layout, (self.site)()) }
+    }
+
+    /// Checks the red zones of `ptr` and frees its block.
+    #[cfg(feature = "heap-debug")]
+    unsafe fn dealloc_object(&mut self, ptr: *mut u8, layout: Layout) {
+        match guarded(layout) {
+            Some(padded) => unsafe {
+                let block = debug::unguard(ptr, layout);
+                self.dealloc_block(block, padded);
+            },
+            None => unsafe {
+                debug::poison(ptr, layout.size());
+                self.dealloc_block(ptr, layout);
+            },
+        }
+    }
+
+    fn alloc_block(&mut self, layout: Layout) -> *mut u8 {
+        match size_class(layout) {
+            Some(class) => {
+                let (buddy, grow) = (&mut self.buddy, self.grow);
+                self.caches[class].alloc(|| alloc_pages(buddy, grow, 0))
+            }
+            None => match buddy::order(layout) {
+                Some(order) => alloc_pages(&mut self.buddy, self.grow, order),
+                None => ptr::null_mut(),
+            },
+        }
+    }
+
+    unsafe fn dealloc_block(&mut self, ptr: *mut u8, layout: Layout) {
+        match size_class(layout) {
+            Some(class) => {
+                let buddy = &mut self.buddy;
+                unsafe { self.caches[class].dealloc(ptr, |page| free_page(buddy, page)) };
+            }
+            None => unsafe { self.buddy.dealloc(ptr, layout) },
+        }
+    }
+}
+
+/// Layout of the block holding an object of `layout` with red zones, if there is a block that
+/// large.
+#[cfg(feature = "heap-debug")]
+fn guarded(layout: Layout) -> Option<Layout> {
+    debug::padded(layout).filter(|&padded| buddy::order(padded).is_some())
+}
+
+/// Allocates a block of `order` from `buddy`, growing the heap with `grow` if there is none.
+fn alloc_pages(buddy: &mut BuddyAllocator, grow: Option<GrowFn>, order: usize) -> *mut u8 {
+    let block = buddy.alloc_pages(order);
+    if !block.is_null() {
+        return block;
+    }
+    let Some(grow) = grow else {
+        return ptr::null_mut();
+    };
+    if buddy.regions_full() {
+        return ptr::null_mut();
+    }
+    // Enough for an aligned block of `order` anywhere in the new memory, and its page states
+    let Some((start, size)) = grow(2 * (PAGE << order) + PAGE) else {
+        return ptr::null_mut();
+    };
+    #[cfg(feature = "heap-debug")]
+    unsafe {
+        debug::poison(start as *mut u8, size)
+    };
+    unsafe { buddy.add_region(start, size) };
+    buddy.alloc_pages(order)
+}
+
+/// Returns a page that held slabs or objects of a [`PageAllocator`] user to `buddy`.
+unsafe fn free_page(buddy: &mut BuddyAllocator, page: *mut u8) {
+    #[cfg(feature = "heap-debug")]
+    unsafe {
+        debug::poison(page, PAGE)
+    };
+    unsafe { buddy.dealloc_pages(page, 0) }
+}
+
+/// Index of the slab cache serving `layout`, if any.
+fn size_class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&class| class >= size)
+}
+
+unsafe impl GlobalAlloc for Locked<Heap> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.lock().dealloc(ptr, layout) }
+    }
+}
+
+impl PageAllocator for Locked<Heap> {
+    fn alloc_page(&self) -> *mut u8 {
+        let mut heap = self.lock();
+        let grow = heap.grow;
+        let page = alloc_pages(&mut heap.buddy, grow, 0);
+        if page.is_null() {
+            heap.counters.failed += 1;
+            return page;
+        }
+        #[cfg(feature = "heap-debug")]
+        unsafe {
+            debug::check_poison(page, PAGE)
+        };
+        heap.counters.count_alloc(PAGE);
+        page
+    }
+
+    unsafe fn dealloc_page(&self, page: *mut u8) {
+        let mut heap = self.lock();
+        heap.counters.count_dealloc(PAGE);
+        unsafe { free_page(&mut heap.buddy, page) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::buddy::MAX_BYTES;
+    use crate::allocator::slab::KmemCache;
+    use crate::test_util::{Rng, aligned_memory};
+
+    fn heap(size: usize) -> Heap {
+        let mut heap = Heap::new();
+        unsafe { heap.init(aligned_memory(size, PAGE), size) };
+        heap
+    }
+
+    // Counts pages, which the red zones of heap debugging change
+    #[test]
+    #[cfg(not(feature = "heap-debug"))]
+    fn small_objects_share_pages() {
+        let mut heap = heap(64 * PAGE);
+        let free = heap.buddy.free_pages();
+        let layout = Layout::new::<u64>();
+        let objects: Vec<_> = (0..100).map(|_| heap.alloc(layout)).collect();
+        assert_eq!(heap.buddy.free_pages(), free - 1);
+        for object in objects {
+            unsafe { heap.dealloc(object, layout) };
+        }
+        assert_eq!(heap.buddy.free_pages(), free);
+    }
+
+    // Counts pages, which the red zones of heap debugging change
+    #[test]
+    #[cfg(not(feature = "heap-debug"))]
+    fn large_objects_come_from_the_buddy() {
+        let mut heap = heap(64 * PAGE);
+        let free = heap.buddy.free_pages();
+        let layout = Layout::from_size_align(3 * PAGE, 8).unwrap();
+        let ptr = heap.alloc(layout);
+        assert!((ptr as usize).is_multiple_of(4 * PAGE));
+        assert_eq!(heap.buddy.free_pages(), free - 4);
+        unsafe { heap.dealloc(ptr, layout) };
+        assert_eq!(heap.buddy.free_pages(), free);
+    }
+
+    #[test]
+    fn alignment_is_honored() {
+        let mut heap = heap(4 * MAX_BYTES);
+        for align in (0..=MAX_BYTES.trailing_zeros()).map(|shift| 1usize << shift) {
+            let layout = Layout::from_size_align(24, align).unwrap();
+            let ptr = heap.alloc(layout);
+            assert!(!ptr.is_null());
+            assert!((ptr as usize).is_multiple_of(align));
+            unsafe { heap.dealloc(ptr, layout) };
+        }
+    }
+
+    #[test]
+    fn page_aligned_structs() {
+        #[repr(align(4096))]
+        struct Table([u64; 512]);
+
+        let heap: &'static Locked<Heap> = Box::leak(Box::new(Locked::new(heap(64 * PAGE))));
+        let free = heap.lock().buddy.free_pages();
+        let layout = Layout::new::<Table>();
+        let tables: Vec<_> = (0..8).map(|_| unsafe { heap.alloc(layout) }).collect();
+        for (i, &table) in tables.iter().enumerate() {
+            assert!(!table.is_null());
+            assert!((table as usize).is_multiple_of(PAGE));
+            unsafe { table.cast::<Table>().write(Table([i as u64; 512])) };
+        }
+        for (i, &table) in tables.iter().enumerate() {
+            assert_eq!(unsafe { &*table.cast::<Table>() }.0, [i as u64; 512]);
+        }
+        // Every table takes a single page, with red zones it takes more
+        if cfg!(not(feature = "heap-debug")) {
+            assert_eq!(heap.lock().buddy.free_pages(), free - 8);
+        }
+        for table in tables {
+            unsafe { heap.dealloc(table, layout) };
+        }
+        assert_eq!(heap.lock().buddy.free_pages(), free);
+    }
+
+    #[test]
+    fn kmem_cache_uses_heap_pages() {
+        let heap: &'static Locked<Heap> = Box::leak(Box::new(Locked::new(heap(64 * PAGE))));
+        let free = heap.lock().buddy.free_pages();
+        let cache = KmemCache::<[u64; 5], _>::new("test", heap);
+        let objects: Vec<_> = (0..10).map(|i| cache.alloc([i; 5]).unwrap()).collect();
+        assert_eq!(heap.lock().buddy.free_pages(), free - 1);
+        for (i, object) in objects.into_iter().enumerate() {
+            assert_eq!(unsafe { object.read() }, [i as u64; 5]);
+            unsafe { cache.free(object) };
+        }
+        assert_eq!(heap.lock().buddy.free_pages(), free);
+    }
+
+    // Counts pages, which the red zones of heap debugging change
+    #[test]
+    #[cfg(not(feature = "heap-debug"))]
+    fn exhausted_heap_grows() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static GROWN: AtomicUsize = AtomicUsize::new(0);
+        fn grow(min_size: usize) -> Option<(usize, usize)> {
+            GROWN.fetch_add(min_size, Ordering::Relaxed);
+            Some((aligned_memory(min_size, PAGE), min_size))
+        }
+
+        let mut heap = Heap::with_grow(grow);
+        let small = Layout::new::<u64>();
+        let large = Layout::from_size_align(MAX_BYTES, 8).unwrap();
+        let (first, second) = (heap.alloc(small), heap.alloc(large));
+        assert!(!first.is_null() && !second.is_null());
+        assert_eq!(
+            GROWN.load(Ordering::Relaxed),
+            3 * PAGE + 2 * MAX_BYTES + PAGE
+        );
+        unsafe {
+            heap.dealloc(first, small);
+            heap.dealloc(second, large);
+        }
+    }
+
+    #[test]
+    fn growth_failure_returns_null() {
+        let mut heap = Heap::with_grow(|_| None);
+        assert!(heap.alloc(Layout::new::<u64>()).is_null());
+    }
+
+    #[test]
+    fn growth_stops_when_regions_run_out() {
+        let mut heap = Heap::with_grow(|min_size| Some((aligned_memory(min_size, PAGE), min_size)));
+        let layout = Layout::from_size_align(PAGE, PAGE).unwrap();
+        let blocks: Vec<_> = core::iter::from_fn(|| Some(heap.alloc(layout)))
+            .take_while(|ptr| !ptr.is_null())
+            .collect();
+        assert!(heap.buddy.regions_full());
+        for block in blocks {
+            unsafe { heap.dealloc(block, layout) };
+        }
+    }
+
+    #[test]
+    fn stats_track_usage() {
+        let mut heap = heap(64 * PAGE);
+        let small = Layout::new::<[u8; 20]>();
+        let large = Layout::from_size_align(2 * PAGE, 8).unwrap();
+        let objects: Vec<_> = (0..3).map(|_| heap.alloc(small)).collect();
+        let block = heap.alloc(large);
+        assert!(
+            heap.alloc(Layout::from_size_align(MAX_BYTES, 8).unwrap())
+                .is_null()
+        );
+        unsafe { heap.dealloc(block, large) };
+
+        let stats = heap.stats();
+        assert_eq!(stats.allocated, 3 * 20 + 2 * PAGE);
+        assert_eq!(stats.freed, 2 * PAGE);
+        assert_eq!(stats.in_use(), 3 * 20);
+        assert_eq!(stats.peak, 3 * 20 + 2 * PAGE);
+        assert_eq!((stats.allocations, stats.frees, stats.failed), (4, 1, 1));
+        let in_use: usize = stats.classes.iter().map(|class| class.in_use).sum();
+        assert_eq!(in_use, 3);
+        let free: usize = (0..ORDERS)
+            .map(|order| stats.free_blocks[order] << order)
+            .sum();
+        assert_eq!(free, stats.free_pages);
+
+        for object in objects {
+            unsafe { heap.dealloc(object, small) };
+        }
+        let stats = heap.stats();
+        assert_eq!(stats.in_use(), 0);
+        assert!(stats.classes.iter().all(|class| class.slabs == 0));
+        assert_eq!(stats.free_pages, stats.total_pages);
+    }
+
+    #[test]
+    fn random_alloc_and_free() {
+        let mut heap = heap(4 * MAX_BYTES);
+        let free = heap.buddy.free_pages();
+        let mut rng = Rng(0x1405_7b7e_f767_814f);
+        let mut live: Vec<(*mut u8, Layout, u8)> = Vec::new();
+
+        for round in 0..20_000 {
+            if live.is_empty() || rng.below(100) < 55 {
+                let size = 1 << rng.below(15);
+                let size = rng.below(size) + 1;
+                let align = 1 << rng.below(8);
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let ptr = heap.alloc(layout);
+                if ptr.is_null() {
+                    continue;
+                }
+                assert!((ptr as usize).is_multiple_of(align));
+                let fill = round as u8;
+                unsafe { ptr.write_bytes(fill, size) };
+                live.push((ptr, layout, fill));
+            } else {
+                let (ptr, layout, fill) = live.swap_remove(rng.below(live.len()));
+                let memory = unsafe { core::slice::from_raw_parts(ptr, layout.size()) };
+                assert!(
+                    memory.iter().all(|&byte| byte == fill),
+                    "memory was clobbered"
+                );
+                unsafe { heap.dealloc(ptr, layout) };
+            }
+        }
+
+        for (ptr, layout, _) in live {
+            unsafe { heap.dealloc(ptr, layout) };
+        }
+        // Every slab went back to the buddy
+        assert_eq!(heap.buddy.free_pages(), free);
+    }
+}
@@ -0,0 +1,402 @@
+use core::marker::PhantomData;
+use core::ptr::{self, NonNull};
+
+use super::buddy::PAGE;
+
+/// A source of page sized, page aligned memory for slab caches.
+pub trait PageAllocator {
+    /// Allocates a page, returns null if there is no memory left.
+    fn alloc_page(&self) -> *mut u8;
+
+    /// Frees a page returned by [`PageAllocator::alloc_page`].
+    ///
+    /// # Safety
+    /// The page must not be used after it is freed.
+    unsafe fn dealloc_page(&self, page: *mut u8);
+}
+
+/// Header at the start of every slab page.
+struct Slab {
+    next: *mut Slab,
+    prev: *mut Slab,
+    free: *mut FreeObject,
+    in_use: usize,
+    object_size: usize,
+}
+
+/// A free object, linked into the free list of its slab.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Objects of one size, carved out of pages.
+///
+/// Every slab is a single page with a [`Slab`] header followed by the objects. Slabs with free
+/// objects are kept on a list, full slabs are only reachable through their objects, and a slab
+/// whose last object is freed is returned to the page allocator right away.
+pub struct SlabCache {
+    object_size: usize,
+    first_object: usize,
+    capacity: usize,
+    partial: *mut Slab,
+    slabs: usize,
+    in_use: usize,
+}
+
+// The slab list only points into pages the cache owns
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    /// Creates a cache for objects of `size` bytes aligned to `align`.
+    ///
+    /// Panics if not a single object fits into a page behind the slab header.
+    pub const fn new(size: usize, align: usize) -> Self {
+        let align = if align > align_of::<FreeObject>() {
+            align
+        } else {
+            align_of::<FreeObject>()
+        };
+        let size = if size > size_of::<FreeObject>() {
+            size
+        } else {
+            size_of::<FreeObject>()
+        };
+        let object_size = size.next_multiple_of(align);
+        let first_object = size_of::<Slab>().next_multiple_of(align);
+        assert!(
+            first_object + object_size <= PAGE,
+            "slab objects must fit into a page"
+        );
+        Self {
+            object_size,
+            first_object,
+            capacity: (PAGE - first_object) / object_size,
+            partial: ptr::null_mut(),
+            slabs: 0,
+            in_use: 0,
+        }
+    }
+
+    /// Size of the objects including their padding.
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    /// Number of objects in a slab.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of slabs, each of them a page.
+    pub fn slabs(&self) -> usize {
+        self.slabs
+    }
+
+    /// Number of objects in use.
+    pub fn in_use(&self) -> usize {
+        self.in_use
+    }
+
+    /// Allocates an object, taking a new slab from `new_page` if all slabs are full.
+    ///
+    /// Returns null if a new slab is needed and `new_page` returns null.
+    pub fn alloc(&mut self, new_page: impl FnOnce() -> *mut u8) -> *mut u8 {
+        if self.partial.is_null() {
+            let page = new_page();
+            if page.is_null() {
+                return ptr::null_mut();
+            }
+            unsafe { self.push(self.init_slab(page)) };
+            self.slabs += 1;
+        }
+        self.in_use += 1;
+
+        let slab = self.partial;
+        unsafe {
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).in_use += 1;
+            if (*slab).free.is_null() {
+                self.remove(slab);
+            }
+            object as *mut u8
+        }
+    }
+
+    /// Frees `object`, handing its slab to `free_page` if it was the last object in use.
+    ///
+    /// # Safety
+    /// `object` must have been allocated from this cache and not freed yet.
+    pub unsafe fn dealloc(&mut self, object: *mut u8, free_page: impl FnOnce(*mut u8)) {
+        let slab = (object as usize & !(PAGE - 1)) as *mut Slab;
+        unsafe {
+            assert_eq!(
+                (*slab).object_size,
+                self.object_size,
+                "freeing object {:p} into the wrong cache",
+                object
+            );
+            let was_full = (*slab).free.is_null();
+            let object = object as *mut FreeObject;
+            (*object).next = (*slab).free;
+            (*slab).free = object;
+            (*slab).in_use -= 1;
+            self.in_use -= 1;
+
+            if (*slab).in_use == 0 {
+                if !was_full {
+                    self.remove(slab);
+                }
+                self.slabs -= 1;
+                free_page(slab as *mut u8);
+            } else if was_full {
+                self.push(slab);
+            }
+        }
+    }
+
+    /// Writes the header of a new slab to `page` and threads its objects onto the free list.
+    unsafe fn init_slab(&self, page: *mut u8) -> *mut Slab {
+        let mut free = ptr::null_mut();
+        for index in (0..self.capacity).rev() {
+            let object = unsafe { page.add(self.first_object + index * self.object_size) };
+            let object = object as *mut FreeObject;
+            unsafe { object.write(FreeObject { next: free }) };
+            free = object;
+        }
+        let slab = page as *mut Slab;
+        unsafe {
+            slab.write(Slab {
+                next: ptr::null_mut(),
+                prev: ptr::null_mut(),
+                free,
+                in_use: 0,
+                object_size: self.object_size,
+            })
+        };
+        slab
+    }
+
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        unsafe {
+            (*slab).prev = ptr::null_mut();
+            (*slab).next = self.partial;
+            if !self.partial.is_null() {
+                (*self.partial).prev = slab;
+            }
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut Slab) {
+        unsafe {
+            let (next, prev) = ((*slab).next, (*slab).prev);
+            if prev.is_null() {
+                self.partial = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+    }
+}
+
+/// A cache of objects of type `T`, for kernel objects that are allocated and freed often.
+///
+/// The slabs of the cache come from `pages`, so objects of different caches never share a page.
+pub struct KmemCache<T, P: PageAllocator + 'static> {
+    name: &'static str,
+    cache: spin::Mutex<SlabCache>,
+    pages: &'static P,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T, P: PageAllocator + 'static> KmemCache<T, P> {
+    pub const fn new(name: &'static str, pages: &'static P) -> Self {
+        Self {
+            name,
+            cache: spin::Mutex::new(SlabCache::new(size_of::<T>(), align_of::<T>())),
+            pages,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Moves `value` into a new object of the cache.
+    ///
+    /// Returns `None` if there is no memory left.
+    pub fn alloc(&self, value: T) -> Option<NonNull<T>> {
+        let object = self.cache.lock().alloc(|| self.pages.alloc_page()) as *mut T;
+        let object = NonNull::new(object)?;
+        unsafe { object.write(value) };
+        Some(object)
+    }
+
+    /// Drops the object and returns its memory to the cache.
+    ///
+    /// # Safety
+    /// `object` must have been allocated from this cache and must not be used afterwards.
+    pub unsafe fn free(&self, object: NonNull<T>) {
+        unsafe {
+            object.drop_in_place();
+            self.cache
+                .lock()
+                .dealloc(object.as_ptr() as *mut u8, |page| {
+                    self.pages.dealloc_page(page)
+                });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{Rng, aligned_memory};
+    use std::cell::RefCell;
+    use std::collections::BTreeSet;
+
+    /// Hands out pages from a leaked buffer and tracks which are in use.
+    struct Pages {
+        free: RefCell<Vec<usize>>,
+        in_use: RefCell<BTreeSet<usize>>,
+    }
+
+    // Tests only use the pages from one thread
+    unsafe impl Sync for Pages {}
+
+    impl Pages {
+        fn new(count: usize) -> Self {
+            let memory = aligned_memory(count * PAGE, PAGE);
+            Self {
+                free: RefCell::new((0..count).map(|page| memory + page * PAGE).collect()),
+                in_use: RefCell::new(BTreeSet::new()),
+            }
+        }
+
+        fn in_use(&self) -> usize {
+            self.in_use.borrow().len()
+        }
+    }
+
+    impl PageAllocator for Pages {
+        fn alloc_page(&self) -> *mut u8 {
+            match self.free.borrow_mut().pop() {
+                Some(page) => {
+                    self.in_use.borrow_mut().insert(page);
+                    page as *mut u8
+                }
+                None => ptr::null_mut(),
+            }
+        }
+
+        unsafe fn dealloc_page(&self, page: *mut u8) {
+            assert!(self.in_use.borrow_mut().remove(&(page as usize)));
+            self.free.borrow_mut().push(page as usize);
+        }
+    }
+
+    #[test]
+    fn object_layout() {
+        let cache = SlabCache::new(1, 1);
+        assert_eq!(cache.object_size(), size_of::<usize>());
+        let cache = SlabCache::new(24, 16);
+        assert_eq!(cache.object_size(), 32);
+        assert_eq!(cache.capacity(), (PAGE - 48) / 32);
+        let cache = SlabCache::new(1024, 1024);
+        assert_eq!(cache.capacity(), 3);
+    }
+
+    #[test]
+    fn empty_slabs_are_returned() {
+        let pages = Pages::new(4);
+        let mut cache = SlabCache::new(1024, 1024);
+        let objects: Vec<_> = (0..6).map(|_| cache.alloc(|| pages.alloc_page())).collect();
+        assert_eq!(pages.in_use(), 2);
+        for &object in &objects {
+            assert!((object as usize).is_multiple_of(1024));
+        }
+        for object in objects {
+            unsafe { cache.dealloc(object, |page| pages.dealloc_page(page)) };
+        }
+        assert_eq!(pages.in_use(), 0);
+    }
+
+    #[test]
+    fn out_of_pages_returns_null() {
+        let pages = Pages::new(1);
+        let mut cache = SlabCache::new(2048, 8);
+        assert!(!cache.alloc(|| pages.alloc_page()).is_null());
+        assert!(cache.alloc(|| pages.alloc_page()).is_null());
+    }
+
+    #[test]
+    #[should_panic(expected = "wrong cache")]
+    fn freeing_into_the_wrong_cache_panics() {
+        let pages = Pages::new(1);
+        let mut small = SlabCache::new(16, 16);
+        let mut large = SlabCache::new(64, 64);
+        let object = small.alloc(|| pages.alloc_page());
+        unsafe { large.dealloc(object, |page| pages.dealloc_page(page)) };
+    }
+
+    #[test]
+    fn kmem_cache_drops_objects() {
+        static PAGES: std::sync::LazyLock<Pages> = std::sync::LazyLock::new(|| Pages::new(2));
+        let cache = KmemCache::<std::rc::Rc<u32>, _>::new("rc", &*PAGES);
+        let value = std::rc::Rc::new(7);
+        let object = cache.alloc(value.clone()).unwrap();
+        assert_eq!(std::rc::Rc::strong_count(&value), 2);
+        unsafe { cache.free(object) };
+        assert_eq!(std::rc::Rc::strong_count(&value), 1);
+        assert_eq!(PAGES.in_use(), 0);
+        assert_eq!(cache.name(), "rc");
+    }
+
+    #[test]
+    fn random_alloc_and_free() {
+        let pages = Pages::new(64);
+        let mut cache = SlabCache::new(40, 8);
+        let mut rng = Rng(0x5851_f42d_4c95_7f2d);
+        let mut live: Vec<(*mut u8, u8)> = Vec::new();
+
+        for round in 0..20_000 {
+            if live.is_empty() || rng.below(100) < 55 {
+                let object = cache.alloc(|| pages.alloc_page());
+                if object.is_null() {
+                    continue;
+                }
+                assert!(
+                    live.iter().all(|&(other, _)| other != object),
+                    "object handed out twice"
+                );
+                let fill = round as u8;
+                unsafe { object.write_bytes(fill, 40) };
+                live.push((object, fill));
+            } else {
+                let (object, fill) = live.swap_remove(rng.below(live.len()));
+                let memory = unsafe { core::slice::from_raw_parts(object, 40) };
+                assert!(
+                    memory.iter().all(|&byte| byte == fill),
+                    "memory was clobbered"
+                );
+                unsafe { cache.dealloc(object, |page| pages.dealloc_page(page)) };
+            }
+            if round % 100 != 0 {
+                continue;
+            }
+            // Only the slabs needed for the live objects are kept
+            let slabs: BTreeSet<_> = live
+                .iter()
+                .map(|&(object, _)| object as usize & !(PAGE - 1))
+                .collect();
+            assert_eq!(slabs.len(), pages.in_use());
+            assert_eq!(cache.slabs(), pages.in_use());
+            assert_eq!(cache.in_use(), live.len());
+        }
+    }
+}
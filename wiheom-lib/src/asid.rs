@@ -0,0 +1,198 @@
+/// An address space identifier, together with the generation it was handed out in.
+///
+/// An ASID from an older generation may have been handed out again and has to be replaced
+/// before its address space runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Asid {
+    generation: u64,
+    value: u16,
+}
+
+impl Asid {
+    /// The value to put into `satp`.
+    pub fn value(self) -> u16 {
+        self.value
+    }
+}
+
+/// Hands out ASIDs to address spaces as they are switched to, for `HARTS` harts.
+///
+/// ASIDs are handed out in order. Once they run out, a new generation starts, every ASID of
+/// the old one expires and all TLBs have to be flushed. The ASIDs running on other harts at
+/// that time are kept for their address spaces, so those do not have to stop. ASID 0 is never
+/// handed out.
+pub struct AsidAllocator<const HARTS: usize> {
+    max: u16,
+    generation: u64,
+    next: u32,
+    active: [Option<Asid>; HARTS],
+    reserved: [Option<Asid>; HARTS],
+}
+
+impl<const HARTS: usize> AsidAllocator<HARTS> {
+    /// Creates an allocator for the ASIDs up to `max`, which is 0 if the harts have no ASIDs.
+    ///
+    /// Less ASIDs than harts are not used at all, as the ASIDs kept through a rollover could
+    /// leave none to hand out.
+    pub const fn new(max: u16) -> Self {
+        Self {
+            max: if (max as usize) < HARTS { 0 } else { max },
+            generation: 1,
+            next: 1,
+            active: [None; HARTS],
+            reserved: [None; HARTS],
+        }
+    }
+
+    /// Returns the ASID to switch to an address space that last ran with `current` on `hart`.
+    ///
+    /// The second value tells if the TLB of `hart` has to be flushed completely, and after a
+    /// rollover, the TLBs of all other harts too.
+    pub fn activate(&mut self, hart: usize, current: Option<Asid>) -> (Asid, bool) {
+        if self.max == 0 {
+            // Every address space shares ASID 0
+            self.generation += 1;
+            let asid = Asid {
+                generation: self.generation,
+                value: 0,
+            };
+            self.active[hart] = Some(asid);
+            return (asid, true);
+        }
+
+        if let Some(asid) = current {
+            let kept = self.reserved.contains(&Some(asid));
+            if asid.generation == self.generation || kept {
+                let asid = Asid {
+                    generation: self.generation,
+                    value: asid.value,
+                };
+                self.active[hart] = Some(asid);
+                return (asid, false);
+            }
+        }
+
+        let mut rollover = false;
+        let value = loop {
+            if self.next > self.max as u32 {
+                self.rollover(hart);
+                rollover = true;
+            }
+            let value = self.next as u16;
+            self.next += 1;
+            if !self.is_reserved(value) {
+                break value;
+            }
+        };
+        let asid = Asid {
+            generation: self.generation,
+            value,
+        };
+        self.active[hart] = Some(asid);
+        (asid, rollover)
+    }
+
+    /// Starts a new generation, keeping the ASIDs of the other harts.
+    fn rollover(&mut self, hart: usize) {
+        self.generation += 1;
+        self.next = 1;
+        for (other, active) in self.active.iter().enumerate() {
+            self.reserved[other] = active.filter(|_| other != hart);
+        }
+    }
+
+    fn is_reserved(&self, value: u16) -> bool {
+        self.reserved
+            .iter()
+            .flatten()
+            .any(|asid| asid.value == value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Rng;
+
+    #[test]
+    fn asids_are_kept_within_a_generation() {
+        let mut asids = AsidAllocator::<1>::new(15);
+        let (first, flush) = asids.activate(0, None);
+        assert_eq!((first.value(), flush), (1, false));
+        let (second, _) = asids.activate(0, None);
+        assert_eq!(second.value(), 2);
+        assert_eq!(asids.activate(0, Some(first)), (first, false));
+    }
+
+    #[test]
+    fn rollover_flushes_and_expires_asids() {
+        let mut asids = AsidAllocator::<1>::new(3);
+        let old: Vec<_> = (0..3).map(|_| asids.activate(0, None).0).collect();
+        let (asid, flush) = asids.activate(0, None);
+        assert!(flush);
+        assert_eq!(asid.value(), 1);
+        let (renewed, flush) = asids.activate(0, Some(old[2]));
+        assert!(!flush);
+        assert_ne!(renewed, old[2]);
+        assert_eq!(renewed.value(), 2);
+    }
+
+    #[test]
+    fn active_asids_of_other_harts_survive_rollover() {
+        let mut asids = AsidAllocator::<2>::new(3);
+        let (running, _) = asids.activate(1, None);
+        asids.activate(0, None);
+        asids.activate(0, None);
+        let (asid, flush) = asids.activate(0, None);
+        assert!(flush);
+        assert_ne!(asid.value(), running.value());
+        // The other hart keeps its ASID without a flush
+        let (kept, flush) = asids.activate(1, Some(running));
+        assert_eq!((kept.value(), flush), (running.value(), false));
+    }
+
+    #[test]
+    fn without_asids_every_switch_flushes() {
+        let mut asids = AsidAllocator::<1>::new(0);
+        let (asid, flush) = asids.activate(0, None);
+        assert_eq!((asid.value(), flush), (0, true));
+        assert!(asids.activate(0, Some(asid)).1);
+    }
+
+    #[test]
+    fn running_asids_are_unique() {
+        const HARTS: usize = 4;
+        let mut asids = AsidAllocator::<HARTS>::new(7);
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        // The ASIDs of 20 address spaces and which of them runs on every hart
+        let mut spaces: [Option<Asid>; 20] = [None; 20];
+        let mut running = [None; HARTS];
+
+        for _ in 0..10_000 {
+            let hart = rng.below(HARTS);
+            let space = loop {
+                let space = rng.below(spaces.len());
+                if running.iter().all(|&other| other != Some(space)) {
+                    break space;
+                }
+            };
+            let (asid, _) = asids.activate(hart, spaces[space]);
+            assert_ne!(asid.value(), 0);
+            spaces[space] = Some(asid);
+            running[hart] = Some(space);
+
+            let values: Vec<_> = running
+                .iter()
+                .flatten()
+                .map(|&space| spaces[space].unwrap().value())
+                .collect();
+            for (i, value) in values.iter().enumerate() {
+                assert!(
+                    !values[i + 1..].contains(value),
+                    "ASID {} runs twice",
+                    value
+                );
+            }
+        }
+    }
+}
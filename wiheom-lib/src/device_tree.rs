@@ -0,0 +1,147 @@
+use core::fmt;
+
+/// Why a `reg` style property could not be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegError {
+    /// The property is shorter than one address and size pair.
+    TooShort {
+        address_cells: u32,
+        size_cells: u32,
+    },
+    UnsupportedAddressCells(u32),
+    UnsupportedSizeCells(u32),
+}
+
+impl fmt::Display for RegError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooShort {
+                address_cells,
+                size_cells,
+            } => write!(
+                f,
+                "'reg' property is too short for address_cells={} and size_cells={}",
+                address_cells, size_cells
+            ),
+            Self::UnsupportedAddressCells(cells) => {
+                write!(f, "Unsupported address_cells value: {}", cells)
+            }
+            Self::UnsupportedSizeCells(cells) => {
+                write!(f, "Unsupported size_cells value: {}", cells)
+            }
+        }
+    }
+}
+
+/// Reads a big endian value of `cells` 32 bit cells from the start of `bytes`.
+fn read_cells(bytes: &[u8], cells: u32) -> Option<usize> {
+    match cells {
+        1 => Some(u32::from_be_bytes(bytes[0..4].try_into().unwrap()) as usize),
+        2 => Some(u64::from_be_bytes(bytes[0..8].try_into().unwrap()) as usize),
+        _ => None,
+    }
+}
+
+/// Reads the first `(address, size)` pair of a 'reg' property based on the address and size
+/// cells
+pub fn read_reg_from_cells(
+    reg: &[u8],
+    address_cells: u32,
+    size_cells: u32,
+) -> Result<(usize, usize), RegError> {
+    if reg.len() < (address_cells + size_cells) as usize * 4 {
+        return Err(RegError::TooShort {
+            address_cells,
+            size_cells,
+        });
+    }
+    let address =
+        read_cells(reg, address_cells).ok_or(RegError::UnsupportedAddressCells(address_cells))?;
+    let size = read_cells(&reg[address_cells as usize * 4..], size_cells)
+        .ok_or(RegError::UnsupportedSizeCells(size_cells))?;
+    Ok((address, size))
+}
+
+/// Iterates over the strings of a string list property like `compatible`.
+///
+/// Strings that are empty or not UTF-8 are skipped.
+pub fn string_list(value: &[u8]) -> impl Iterator<Item = &str> {
+    value
+        .split(|&byte| byte == 0)
+        .filter(|string| !string.is_empty())
+        .filter_map(|string| core::str::from_utf8(string).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_cell_each() {
+        let reg = [0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00];
+        assert_eq!(read_reg_from_cells(&reg, 1, 1), Ok((0x1000_0000, 0x100)));
+    }
+
+    #[test]
+    fn two_cells_each() {
+        let reg = [
+            0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, // address
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // size
+        ];
+        assert_eq!(read_reg_from_cells(&reg, 2, 2), Ok((0x8000_0000, 1 << 32)));
+    }
+
+    #[test]
+    fn mixed_cells() {
+        let reg = [
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // address
+            0x00, 0x00, 0x10, 0x00, // size
+        ];
+        assert_eq!(read_reg_from_cells(&reg, 2, 1), Ok((1 << 32, 0x1000)));
+    }
+
+    #[test]
+    fn only_first_pair_is_read() {
+        let reg = [0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4];
+        assert_eq!(read_reg_from_cells(&reg, 1, 1), Ok((1, 2)));
+    }
+
+    #[test]
+    fn too_short() {
+        let reg = [0; 12];
+        assert_eq!(
+            read_reg_from_cells(&reg, 2, 2),
+            Err(RegError::TooShort {
+                address_cells: 2,
+                size_cells: 2
+            })
+        );
+    }
+
+    #[test]
+    fn unsupported_cells() {
+        let reg = [0; 16];
+        assert_eq!(
+            read_reg_from_cells(&reg, 3, 1),
+            Err(RegError::UnsupportedAddressCells(3))
+        );
+        assert_eq!(
+            read_reg_from_cells(&reg, 1, 0),
+            Err(RegError::UnsupportedSizeCells(0))
+        );
+    }
+
+    #[test]
+    fn strings_of_a_list() {
+        let value = b"i\0m\0zicbom\0svpbmt\0";
+        let strings: Vec<_> = string_list(value).collect();
+        assert_eq!(strings, ["i", "m", "zicbom", "svpbmt"]);
+    }
+
+    #[test]
+    fn empty_and_invalid_strings_are_skipped() {
+        let value = b"\0a\0\0\xff\0b";
+        let strings: Vec<_> = string_list(value).collect();
+        assert_eq!(strings, ["a", "b"]);
+    }
+}
@@ -0,0 +1,309 @@
+use memory_addr::{PAGE_SIZE_4K, PhysAddr};
+
+const BITS: usize = u64::BITS as usize;
+
+/// A physical frame allocator that keeps one bit per 4K frame.
+///
+/// A set bit marks the frame as used. Every frame starts out used, usable memory has to be
+/// handed to the allocator with [`BitmapAllocator::add_range`].
+pub struct BitmapAllocator {
+    base: usize,
+    frames: usize,
+    free: usize,
+    next: usize,
+    bitmap: &'static mut [u64],
+}
+
+impl BitmapAllocator {
+    /// Creates an allocator that can track no frames.
+    pub const fn empty() -> Self {
+        Self {
+            base: 0,
+            frames: 0,
+            free: 0,
+            next: 0,
+            bitmap: &mut [],
+        }
+    }
+
+    /// Number of bytes of bitmap needed to track `frames` frames.
+    pub const fn bitmap_size(frames: usize) -> usize {
+        frames.div_ceil(BITS) * size_of::<u64>()
+    }
+
+    /// Creates an allocator covering `frames` frames starting at the physical address `base`.
+    ///
+    /// All frames are marked as used.
+    pub fn new(base: usize, frames: usize, bitmap: &'static mut [u64]) -> Self {
+        assert!(
+            base.is_multiple_of(PAGE_SIZE_4K),
+            "frame allocator base is not page aligned"
+        );
+        assert!(bitmap.len() * BITS >= frames, "frame bitmap is too small");
+        bitmap.fill(u64::MAX);
+        Self {
+            base,
+            frames,
+            free: 0,
+            next: 0,
+            bitmap,
+        }
+    }
+
+    /// Marks the frames in `start..end` as free.
+    ///
+    /// The range is shrunk to whole frames and clipped to the frames tracked by the allocator.
+    pub fn add_range(&mut self, start: usize, end: usize) {
+        let (first, last) = self.frame_range(start.next_multiple_of(PAGE_SIZE_4K), end);
+        for frame in first..last {
+            if self.is_used(frame) {
+                self.clear(frame);
+                self.free += 1;
+            }
+        }
+        self.next = self.next.min(first);
+    }
+
+    /// Marks the frames overlapping `start..end` as used.
+    pub fn reserve_range(&mut self, start: usize, end: usize) {
+        let (first, last) = self.frame_range(start, end.next_multiple_of(PAGE_SIZE_4K));
+        for frame in first..last {
+            if !self.is_used(frame) {
+                self.set(frame);
+                self.free -= 1;
+            }
+        }
+    }
+
+    /// Allocates a single frame.
+    pub fn alloc(&mut self) -> Option<PhysAddr> {
+        let words = self.frames.div_ceil(BITS);
+        let start = self.next / BITS;
+        for word in (start..words).chain(0..start) {
+            let bits = self.bitmap[word];
+            if bits == u64::MAX {
+                continue;
+            }
+            let frame = word * BITS + bits.trailing_ones() as usize;
+            if frame >= self.frames {
+                continue;
+            }
+            self.set(frame);
+            self.free -= 1;
+            self.next = frame + 1;
+            return Some(self.frame_addr(frame));
+        }
+        None
+    }
+
+    /// Allocates `count` physically contiguous frames.
+    ///
+    /// The physical address of the first frame is aligned to `align` frames, which must be a
+    /// power of two.
+    pub fn alloc_contiguous(&mut self, count: usize, align: usize) -> Option<PhysAddr> {
+        assert!(
+            align.is_power_of_two(),
+            "frame alignment must be a power of two"
+        );
+        if count == 0 || count > self.free {
+            return None;
+        }
+        let first_pfn = self.base / PAGE_SIZE_4K;
+        let mut frame = first_pfn.next_multiple_of(align) - first_pfn;
+        while frame + count <= self.frames {
+            match (frame..frame + count).rev().find(|&f| self.is_used(f)) {
+                Some(used) => {
+                    // Restart the search at the first aligned frame after the used one
+                    frame = (first_pfn + used + 1).next_multiple_of(align) - first_pfn;
+                }
+                None => {
+                    for f in frame..frame + count {
+                        self.set(f);
+                    }
+                    self.free -= count;
+                    return Some(self.frame_addr(frame));
+                }
+            }
+        }
+        None
+    }
+
+    /// Frees a frame returned by [`BitmapAllocator::alloc`].
+    pub fn dealloc(&mut self, paddr: PhysAddr) {
+        self.dealloc_contiguous(paddr, 1);
+    }
+
+    /// Frees `count` frames returned by [`BitmapAllocator::alloc_contiguous`].
+    pub fn dealloc_contiguous(&mut self, paddr: PhysAddr, count: usize) {
+        let first = self.frame_index(paddr);
+        for frame in first..first + count {
+            assert!(
+                frame < self.frames && self.is_used(frame),
+                "freeing frame {:#x} that is not allocated",
+                self.base + frame * PAGE_SIZE_4K
+            );
+            self.clear(frame);
+        }
+        self.free += count;
+        self.next = self.next.min(first);
+    }
+
+    /// Number of frames that can currently be allocated.
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    /// Number of frames tracked by the allocator, used or not.
+    pub fn total_frames(&self) -> usize {
+        self.frames
+    }
+
+    fn frame_index(&self, paddr: PhysAddr) -> usize {
+        let paddr = paddr.as_usize();
+        assert!(
+            paddr >= self.base && paddr.is_multiple_of(PAGE_SIZE_4K),
+            "invalid frame address {:#x}",
+            paddr
+        );
+        (paddr - self.base) / PAGE_SIZE_4K
+    }
+
+    fn frame_addr(&self, frame: usize) -> PhysAddr {
+        PhysAddr::from_usize(self.base + frame * PAGE_SIZE_4K)
+    }
+
+    /// Frame indices of the frames that start in `start..end`, clipped to the tracked frames.
+    fn frame_range(&self, start: usize, end: usize) -> (usize, usize) {
+        let end_addr = self.base + self.frames * PAGE_SIZE_4K;
+        let start = start.clamp(self.base, end_addr);
+        let end = end.clamp(start, end_addr);
+        (
+            (start - self.base) / PAGE_SIZE_4K,
+            (end - self.base) / PAGE_SIZE_4K,
+        )
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS] & (1 << (frame % BITS)) != 0
+    }
+
+    fn set(&mut self, frame: usize) {
+        self.bitmap[frame / BITS] |= 1 << (frame % BITS);
+    }
+
+    fn clear(&mut self, frame: usize) {
+        self.bitmap[frame / BITS] &= !(1 << (frame % BITS));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Rng;
+
+    const BASE: usize = 0x8000_0000;
+
+    fn allocator(frames: usize) -> BitmapAllocator {
+        let bitmap = vec![0; BitmapAllocator::bitmap_size(frames) / size_of::<u64>()];
+        BitmapAllocator::new(BASE, frames, Vec::leak(bitmap))
+    }
+
+    fn frame(index: usize) -> PhysAddr {
+        PhysAddr::from_usize(BASE + index * PAGE_SIZE_4K)
+    }
+
+    #[test]
+    fn frames_start_used() {
+        let mut allocator = allocator(100);
+        assert_eq!(allocator.free_frames(), 0);
+        assert_eq!(allocator.total_frames(), 100);
+        assert_eq!(allocator.alloc(), None);
+    }
+
+    #[test]
+    fn add_range_is_clipped_to_whole_frames() {
+        let mut allocator = allocator(100);
+        allocator.add_range(BASE - PAGE_SIZE_4K, BASE + 2 * PAGE_SIZE_4K + 1);
+        assert_eq!(allocator.free_frames(), 2);
+        allocator.add_range(BASE + 10 * PAGE_SIZE_4K + 1, BASE + 200 * PAGE_SIZE_4K);
+        assert_eq!(allocator.free_frames(), 2 + 89);
+    }
+
+    #[test]
+    fn reserve_range_covers_partial_frames() {
+        let mut allocator = allocator(100);
+        allocator.add_range(BASE, BASE + 100 * PAGE_SIZE_4K);
+        allocator.reserve_range(BASE + 1, BASE + PAGE_SIZE_4K + 1);
+        assert_eq!(allocator.free_frames(), 98);
+        assert_eq!(allocator.alloc(), Some(frame(2)));
+    }
+
+    #[test]
+    fn alloc_and_dealloc() {
+        let mut allocator = allocator(3);
+        allocator.add_range(BASE, BASE + 3 * PAGE_SIZE_4K);
+        let frames: Vec<_> = core::iter::from_fn(|| allocator.alloc()).collect();
+        assert_eq!(frames, [frame(0), frame(1), frame(2)]);
+        allocator.dealloc(frame(1));
+        assert_eq!(allocator.alloc(), Some(frame(1)));
+        assert_eq!(allocator.alloc(), None);
+    }
+
+    #[test]
+    fn contiguous_respects_alignment_and_holes() {
+        let mut allocator = allocator(64);
+        allocator.add_range(BASE, BASE + 64 * PAGE_SIZE_4K);
+        allocator.reserve_range(frame(9).as_usize(), frame(10).as_usize());
+        assert_eq!(allocator.alloc_contiguous(8, 8), Some(frame(0)));
+        // Frame 9 is used, so the next aligned run of 8 starts at 16
+        assert_eq!(allocator.alloc_contiguous(8, 8), Some(frame(16)));
+        assert_eq!(allocator.alloc_contiguous(4, 1), Some(frame(10)));
+        allocator.dealloc_contiguous(frame(0), 8);
+        assert_eq!(allocator.free_frames(), 64 - 1 - 8 - 4);
+        assert_eq!(allocator.alloc_contiguous(64, 1), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "not allocated")]
+    fn double_free_panics() {
+        let mut allocator = allocator(8);
+        allocator.add_range(BASE, BASE + 8 * PAGE_SIZE_4K);
+        let paddr = allocator.alloc().unwrap();
+        allocator.dealloc(paddr);
+        allocator.dealloc(paddr);
+    }
+
+    #[test]
+    fn random_alloc_and_free() {
+        const FRAMES: usize = 1000;
+        let mut allocator = allocator(FRAMES);
+        allocator.add_range(BASE, BASE + FRAMES * PAGE_SIZE_4K);
+        let mut rng = Rng(0xdead_beef_cafe_f00d);
+        let mut used = vec![false; FRAMES];
+        let mut live: Vec<(PhysAddr, usize)> = Vec::new();
+
+        for _ in 0..20_000 {
+            if live.is_empty() || rng.below(100) < 55 {
+                let count = rng.below(16) + 1;
+                let align = 1 << rng.below(4);
+                let Some(paddr) = allocator.alloc_contiguous(count, align) else {
+                    continue;
+                };
+                let first = (paddr.as_usize() - BASE) / PAGE_SIZE_4K;
+                assert!(first.is_multiple_of(align));
+                for used in &mut used[first..first + count] {
+                    assert!(!*used, "frame handed out twice");
+                    *used = true;
+                }
+                live.push((paddr, count));
+            } else {
+                let (paddr, count) = live.swap_remove(rng.below(live.len()));
+                let first = (paddr.as_usize() - BASE) / PAGE_SIZE_4K;
+                used[first..first + count].fill(false);
+                allocator.dealloc_contiguous(paddr, count);
+            }
+            let free = used.iter().filter(|&&used| !used).count();
+            assert_eq!(allocator.free_frames(), free);
+        }
+    }
+}
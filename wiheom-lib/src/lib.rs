@@ -0,0 +1,16 @@
+//! Hardware independent parts of the kernel.
+//!
+//! Everything here builds for the host as well, so it can be tested with `cargo test`.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod allocator;
+pub mod asid;
+pub mod device_tree;
+pub mod frame;
+pub mod memory_map;
+pub mod numa;
+pub mod page;
+
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,366 @@
+use core::fmt;
+
+/// Maximum number of regions a [`MemoryMap`] can hold.
+const MAX_REGIONS: usize = 64;
+
+/// What a region of physical memory is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Free RAM handed to the frame allocator.
+    Usable,
+    /// RAM below the kernel that belongs to the SBI firmware.
+    Firmware,
+    /// The kernel image.
+    Kernel,
+    /// The flattened device tree blob.
+    DeviceTree,
+    /// The bitmap of the frame allocator.
+    FrameBitmap,
+    /// The metadata of every frame.
+    PageArray,
+    /// Reserved by the device tree, the kernel must not allocate from it.
+    Reserved,
+    /// Reserved by the device tree with `no-map`, the kernel must not map it either.
+    ReservedNoMap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    const EMPTY: Region = Region {
+        start: 0,
+        end: 0,
+        kind: RegionKind::Usable,
+    };
+
+    pub fn size(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{:#012x} - {:#012x}] {:>8} KiB {:?}",
+            self.start,
+            self.end,
+            self.size() / 1024,
+            self.kind
+        )
+    }
+}
+
+/// A sorted list of non-overlapping physical memory regions.
+///
+/// The map is built before the heap exists, so it is backed by a fixed size array.
+pub struct MemoryMap {
+    regions: [Region; MAX_REGIONS],
+    len: usize,
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryMap {
+    pub const fn new() -> Self {
+        Self {
+            regions: [Region::EMPTY; MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    /// Adds the RAM in `start..end` as usable memory.
+    pub fn add_ram(&mut self, start: usize, end: usize) {
+        self.carve(start, end, RegionKind::Usable, true);
+    }
+
+    /// Marks the RAM in `start..end` as `kind`.
+    ///
+    /// Only the parts of the range that are already in the map are changed, so reserving memory
+    /// outside of RAM is a no-op.
+    pub fn reserve(&mut self, start: usize, end: usize, kind: RegionKind) {
+        self.carve(start, end, kind, false);
+    }
+
+    /// Finds the lowest usable range of `size` bytes aligned to `align` and marks it as `kind`.
+    pub fn allocate(&mut self, size: usize, align: usize, kind: RegionKind) -> Option<usize> {
+        self.allocate_within(size, align, 0, usize::MAX, kind)
+    }
+
+    /// Like [`MemoryMap::allocate`], but the range has to lie within `low..high`.
+    pub fn allocate_within(
+        &mut self,
+        size: usize,
+        align: usize,
+        low: usize,
+        high: usize,
+        kind: RegionKind,
+    ) -> Option<usize> {
+        let start = self
+            .usable()
+            .map(|region| {
+                (
+                    region.start.max(low).next_multiple_of(align),
+                    region.end.min(high),
+                )
+            })
+            .find(|&(start, end)| start + size <= end)
+            .map(|(start, _)| start)?;
+        self.reserve(start, start + size, kind);
+        Some(start)
+    }
+
+    /// All regions in ascending address order.
+    pub fn iter(&self) -> impl Iterator<Item = &Region> {
+        self.regions[..self.len].iter()
+    }
+
+    /// All usable regions in ascending address order.
+    pub fn usable(&self) -> impl Iterator<Item = &Region> {
+        self.iter()
+            .filter(|region| region.kind == RegionKind::Usable)
+    }
+
+    /// Lowest and highest address covered by the map.
+    pub fn span(&self) -> Option<(usize, usize)> {
+        let first = self.regions[..self.len].first()?;
+        let last = self.regions[..self.len].last()?;
+        Some((first.start, last.end))
+    }
+
+    /// Sets `start..end` to `kind`. When `insert` is false only the parts that overlap
+    /// existing regions are changed.
+    fn carve(&mut self, start: usize, end: usize, kind: RegionKind, insert: bool) {
+        if start >= end {
+            return;
+        }
+
+        let mut i = 0;
+        let mut cursor = start;
+        while i < self.len && cursor < end {
+            let region = self.regions[i];
+            if region.end <= cursor {
+                i += 1;
+                continue;
+            }
+            if region.start >= end {
+                break;
+            }
+
+            if cursor < region.start {
+                // Gap before this region
+                if insert {
+                    self.insert_at(
+                        i,
+                        Region {
+                            start: cursor,
+                            end: region.start,
+                            kind,
+                        },
+                    );
+                    i += 1;
+                }
+                cursor = region.start;
+                continue;
+            }
+
+            // `region` contains `cursor`: split it into the parts before, inside and after
+            let overlap_end = region.end.min(end);
+            let mut next = i;
+            if region.start < cursor {
+                self.regions[next].end = cursor;
+                next += 1;
+                self.insert_at(
+                    next,
+                    Region {
+                        start: cursor,
+                        ..region
+                    },
+                );
+            }
+            self.regions[next].end = overlap_end;
+            self.regions[next].kind = kind;
+            if overlap_end < region.end {
+                self.insert_at(
+                    next + 1,
+                    Region {
+                        start: overlap_end,
+                        ..region
+                    },
+                );
+            }
+            cursor = overlap_end;
+            i = next + 1;
+        }
+
+        if insert && cursor < end {
+            self.insert_at(
+                i,
+                Region {
+                    start: cursor,
+                    end,
+                    kind,
+                },
+            );
+        }
+
+        self.merge();
+    }
+
+    fn insert_at(&mut self, index: usize, region: Region) {
+        assert!(self.len < MAX_REGIONS, "memory map is full");
+        self.regions.copy_within(index..self.len, index + 1);
+        self.regions[index] = region;
+        self.len += 1;
+    }
+
+    /// Joins adjacent regions of the same kind.
+    fn merge(&mut self) {
+        let mut i = 1;
+        while i < self.len {
+            let (prev, region) = (self.regions[i - 1], self.regions[i]);
+            if prev.end == region.start && prev.kind == region.kind {
+                self.regions[i - 1].end = region.end;
+                self.regions.copy_within(i + 1..self.len, i);
+                self.len -= 1;
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Rng;
+
+    fn regions(map: &MemoryMap) -> Vec<(usize, usize, RegionKind)> {
+        map.iter()
+            .map(|region| (region.start, region.end, region.kind))
+            .collect()
+    }
+
+    #[test]
+    fn ram_banks_are_merged() {
+        let mut map = MemoryMap::new();
+        map.add_ram(0x2000, 0x3000);
+        map.add_ram(0x1000, 0x2000);
+        map.add_ram(0x5000, 0x6000);
+        assert_eq!(
+            regions(&map),
+            [
+                (0x1000, 0x3000, RegionKind::Usable),
+                (0x5000, 0x6000, RegionKind::Usable)
+            ]
+        );
+        assert_eq!(map.span(), Some((0x1000, 0x6000)));
+    }
+
+    #[test]
+    fn reserve_splits_regions() {
+        let mut map = MemoryMap::new();
+        map.add_ram(0x1000, 0x9000);
+        map.reserve(0x3000, 0x4000, RegionKind::Kernel);
+        assert_eq!(
+            regions(&map),
+            [
+                (0x1000, 0x3000, RegionKind::Usable),
+                (0x3000, 0x4000, RegionKind::Kernel),
+                (0x4000, 0x9000, RegionKind::Usable)
+            ]
+        );
+        map.reserve(0x4000, 0x5000, RegionKind::Kernel);
+        assert_eq!(regions(&map)[1], (0x3000, 0x5000, RegionKind::Kernel));
+    }
+
+    #[test]
+    fn reserve_outside_of_ram_is_ignored() {
+        let mut map = MemoryMap::new();
+        map.add_ram(0x1000, 0x2000);
+        map.add_ram(0x4000, 0x5000);
+        map.reserve(0x0, 0x6000, RegionKind::Reserved);
+        assert_eq!(
+            regions(&map),
+            [
+                (0x1000, 0x2000, RegionKind::Reserved),
+                (0x4000, 0x5000, RegionKind::Reserved)
+            ]
+        );
+    }
+
+    #[test]
+    fn allocate_finds_aligned_usable_memory() {
+        let mut map = MemoryMap::new();
+        map.add_ram(0x1000, 0x10000);
+        map.reserve(0x1000, 0x3000, RegionKind::Firmware);
+        assert_eq!(
+            map.allocate(0x1000, 0x4000, RegionKind::FrameBitmap),
+            Some(0x4000)
+        );
+        assert_eq!(
+            map.allocate_within(0x2000, 0x1000, 0x8000, 0x9000, RegionKind::Reserved),
+            None
+        );
+        assert_eq!(
+            map.allocate_within(0x1000, 0x1000, 0x8000, 0x9000, RegionKind::Reserved),
+            Some(0x8000)
+        );
+        assert_eq!(map.allocate(0x10000, 0x1000, RegionKind::Reserved), None);
+    }
+
+    #[test]
+    fn random_reservations_match_a_page_model() {
+        const PAGES: usize = 256;
+        const KINDS: [RegionKind; 4] = [
+            RegionKind::Usable,
+            RegionKind::Kernel,
+            RegionKind::Reserved,
+            RegionKind::ReservedNoMap,
+        ];
+        let mut rng = Rng(0x0123_4567_89ab_cdef);
+        for _ in 0..200 {
+            let mut map = MemoryMap::new();
+            let mut model: Vec<Option<RegionKind>> = vec![None; PAGES];
+            for _ in 0..20 {
+                let start = rng.below(PAGES);
+                let end = start + rng.below(PAGES - start) + 1;
+                if rng.below(3) == 0 {
+                    map.add_ram(start, end);
+                    model[start..end].fill(Some(RegionKind::Usable));
+                } else {
+                    let kind = KINDS[rng.below(KINDS.len())];
+                    map.reserve(start, end, kind);
+                    for page in model[start..end].iter_mut().flatten() {
+                        *page = kind;
+                    }
+                }
+            }
+
+            let mut expected = vec![None; PAGES];
+            let mut prev: Option<&Region> = None;
+            for region in map.iter() {
+                assert!(region.start < region.end);
+                if let Some(prev) = prev {
+                    assert!(prev.end <= region.start, "regions overlap or are unsorted");
+                    assert!(
+                        prev.end != region.start || prev.kind != region.kind,
+                        "adjacent regions of the same kind were not merged"
+                    );
+                }
+                expected[region.start..region.end].fill(Some(region.kind));
+                prev = Some(region);
+            }
+            assert_eq!(expected, model);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+/// Maximum number of NUMA nodes the kernel keeps apart, memory of higher nodes is treated as
+/// part of the last one.
+pub const MAX_NODES: usize = 8;
+
+/// Distance of a node to itself, as in the `distance-map` binding.
+pub const LOCAL_DISTANCE: u32 = 10;
+
+/// Distance between two different nodes when the device tree does not say otherwise.
+pub const REMOTE_DISTANCE: u32 = 20;
+
+/// The relative cost of accessing the memory of one node from another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Distances {
+    nodes: usize,
+    matrix: [[u32; MAX_NODES]; MAX_NODES],
+}
+
+impl Distances {
+    /// Default distances between `nodes` nodes, local memory is closer than all remote memory.
+    pub const fn new(nodes: usize) -> Self {
+        let nodes = if nodes == 0 {
+            1
+        } else if nodes > MAX_NODES {
+            MAX_NODES
+        } else {
+            nodes
+        };
+        let mut matrix = [[REMOTE_DISTANCE; MAX_NODES]; MAX_NODES];
+        let mut node = 0;
+        while node < MAX_NODES {
+            matrix[node][node] = LOCAL_DISTANCE;
+            node += 1;
+        }
+        Self { nodes, matrix }
+    }
+
+    pub fn nodes(&self) -> usize {
+        self.nodes
+    }
+
+    /// Sets the distance from `from` to `to`, entries for unknown nodes are ignored.
+    pub fn set(&mut self, from: usize, to: usize, distance: u32) {
+        if from < self.nodes && to < self.nodes {
+            self.matrix[from][to] = distance;
+        }
+    }
+
+    pub fn distance(&self, from: usize, to: usize) -> u32 {
+        self.matrix[from][to]
+    }
+
+    /// The distances from `node` to every node.
+    pub fn row(&self, node: usize) -> &[u32] {
+        &self.matrix[node][..self.nodes]
+    }
+
+    /// All nodes ordered by their distance from `node`, the order allocations for `node` fall
+    /// back in.
+    ///
+    /// `node` itself always comes first, nodes at the same distance are ordered by their id.
+    pub fn fallback_order(&self, node: usize) -> impl Iterator<Item = usize> + use<> {
+        let node = node.min(self.nodes - 1);
+        let mut order = [0; MAX_NODES];
+        for (index, slot) in order.iter_mut().enumerate() {
+            *slot = index;
+        }
+        let distances = self.matrix[node];
+        order[..self.nodes].sort_unstable_by_key(|&other| (other != node, distances[other], other));
+        order.into_iter().take(self.nodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_distances_prefer_the_local_node() {
+        let distances = Distances::new(3);
+        assert_eq!(distances.distance(1, 1), LOCAL_DISTANCE);
+        assert_eq!(distances.distance(1, 2), REMOTE_DISTANCE);
+        assert_eq!(distances.row(0), [10, 20, 20]);
+        assert!(distances.fallback_order(1).eq([1, 0, 2]));
+    }
+
+    #[test]
+    fn fallback_follows_the_distance_map() {
+        let mut distances = Distances::new(4);
+        for (from, to, distance) in [(0, 1, 40), (0, 2, 15), (0, 3, 20)] {
+            distances.set(from, to, distance);
+        }
+        assert!(distances.fallback_order(0).eq([0, 2, 3, 1]));
+    }
+
+    #[test]
+    fn unknown_nodes_are_ignored() {
+        let mut distances = Distances::new(2);
+        distances.set(0, 5, 1);
+        distances.set(MAX_NODES, 0, 1);
+        assert!(distances.fallback_order(7).eq([1, 0]));
+        assert_eq!(Distances::new(0).nodes(), 1);
+        assert_eq!(Distances::new(100).nodes(), MAX_NODES);
+    }
+}
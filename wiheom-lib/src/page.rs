@@ -0,0 +1,327 @@
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use bitflags::bitflags;
+use memory_addr::{PAGE_SIZE_4K, PhysAddr};
+
+bitflags! {
+    /// What a frame is used for.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PageFlags: u32 {
+        /// Used by the kernel itself, like its image or page tables.
+        const KERNEL = 1 << 0;
+        /// Mapped into a user address space.
+        const USER = 1 << 1;
+        /// Caches the contents of a file.
+        const PAGE_CACHE = 1 << 2;
+        /// Part of the kernel heap, carved up by the slab caches.
+        const SLAB = 1 << 3;
+        /// Not RAM the allocator may hand out, it is never freed.
+        const RESERVED = 1 << 4;
+    }
+}
+
+/// Marks the end of a [`FrameList`].
+const NO_FRAME: usize = usize::MAX;
+
+/// Metadata of a single 4K frame of physical memory.
+///
+/// A frame is free while its reference count is 0. The allocator hands it out with one
+/// reference, every further user takes another one with [`Page::get`], and the frame is freed
+/// when the last one is dropped with [`Page::put`].
+#[derive(Debug)]
+pub struct Page {
+    refcount: AtomicU32,
+    flags: AtomicU32,
+    owner: AtomicUsize,
+    next: AtomicUsize,
+}
+
+impl Page {
+    /// A reserved frame, which is never freed.
+    const fn reserved() -> Self {
+        Self {
+            refcount: AtomicU32::new(1),
+            flags: AtomicU32::new(PageFlags::RESERVED.bits()),
+            owner: AtomicUsize::new(0),
+            next: AtomicUsize::new(NO_FRAME),
+        }
+    }
+
+    /// Number of users of the frame, 0 if it is free.
+    pub fn refcount(&self) -> u32 {
+        self.refcount.load(Ordering::Acquire)
+    }
+
+    pub fn flags(&self) -> PageFlags {
+        PageFlags::from_bits_truncate(self.flags.load(Ordering::Relaxed))
+    }
+
+    /// What the frame belongs to, like the address space mapping it, 0 if that is not known.
+    pub fn owner(&self) -> usize {
+        self.owner.load(Ordering::Relaxed)
+    }
+
+    pub fn set_owner(&self, owner: usize) {
+        self.owner.store(owner, Ordering::Relaxed);
+    }
+
+    /// Sets up a frame that was just allocated, with a single reference.
+    ///
+    /// Panics if the frame is still in use.
+    pub fn allocated(&self, flags: PageFlags) {
+        self.owner.store(0, Ordering::Relaxed);
+        self.flags.store(flags.bits(), Ordering::Relaxed);
+        let old = self.refcount.swap(1, Ordering::AcqRel);
+        assert_eq!(old, 0, "allocated a frame that is still in use");
+    }
+
+    /// Takes another reference to a frame that is in use.
+    pub fn get(&self) {
+        let old = self.refcount.fetch_add(1, Ordering::Relaxed);
+        assert!(old > 0, "taking a reference to a free frame");
+    }
+
+    /// Drops a reference, and returns whether it was the last one so the frame has to be freed.
+    ///
+    /// Reserved frames are never freed.
+    pub fn put(&self) -> bool {
+        let old = self.refcount.fetch_sub(1, Ordering::AcqRel);
+        assert!(old > 0, "dropping a reference to a free frame");
+        if old > 1 {
+            return false;
+        }
+        if self.flags().contains(PageFlags::RESERVED) {
+            self.refcount.store(1, Ordering::Relaxed);
+            return false;
+        }
+        self.flags.store(0, Ordering::Relaxed);
+        self.owner.store(0, Ordering::Relaxed);
+        true
+    }
+
+    /// Marks a frame as free RAM.
+    fn free(&self) {
+        self.refcount.store(0, Ordering::Relaxed);
+        self.flags.store(0, Ordering::Relaxed);
+    }
+}
+
+/// The [`Page`] of every frame in a range of physical memory.
+pub struct PageArray {
+    base: usize,
+    pages: &'static [Page],
+}
+
+impl PageArray {
+    /// An array covering no frames.
+    pub const fn empty() -> Self {
+        Self {
+            base: 0,
+            pages: &[],
+        }
+    }
+
+    /// Number of bytes needed for the metadata of `frames` frames.
+    pub const fn array_size(frames: usize) -> usize {
+        frames * size_of::<Page>()
+    }
+
+    /// Creates the metadata of the frames starting at the physical address `base`, one for every
+    /// element of `memory`.
+    ///
+    /// All frames start out reserved, free RAM has to be marked with [`PageArray::free_range`].
+    pub fn new(base: usize, memory: &'static mut [MaybeUninit<Page>]) -> Self {
+        assert!(
+            base.is_multiple_of(PAGE_SIZE_4K),
+            "page array base is not page aligned"
+        );
+        for page in memory.iter_mut() {
+            page.write(Page::reserved());
+        }
+        // Every element was initialized above
+        let pages = unsafe { &*(memory as *mut [MaybeUninit<Page>] as *const [Page]) };
+        Self { base, pages }
+    }
+
+    /// Marks the frames in `start..end` as free, clipped to the covered frames.
+    pub fn free_range(&self, start: usize, end: usize) {
+        for page in self.range(start, end) {
+            page.free();
+        }
+    }
+
+    /// Adds `flags` to the frames in `start..end`, clipped to the covered frames.
+    pub fn mark_range(&self, start: usize, end: usize, flags: PageFlags) {
+        for page in self.range(start, end) {
+            page.flags.fetch_or(flags.bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// The metadata of the frame at `paddr`, if it is covered.
+    pub fn page(&self, paddr: PhysAddr) -> Option<&Page> {
+        let offset = paddr.as_usize().checked_sub(self.base)?;
+        self.pages.get(offset / PAGE_SIZE_4K)
+    }
+
+    /// Number of frames in each state, as `(free, reserved, in use)`.
+    pub fn counts(&self) -> (usize, usize, usize) {
+        let mut counts = (0, 0, 0);
+        for page in self.pages {
+            if page.refcount() == 0 {
+                counts.0 += 1;
+            } else if page.flags().contains(PageFlags::RESERVED) {
+                counts.1 += 1;
+            } else {
+                counts.2 += 1;
+            }
+        }
+        counts
+    }
+
+    fn range(&self, start: usize, end: usize) -> &[Page] {
+        let end_addr = self.base + self.pages.len() * PAGE_SIZE_4K;
+        let start = start.clamp(self.base, end_addr);
+        let end = end.clamp(start, end_addr);
+        &self.pages[(start - self.base) / PAGE_SIZE_4K..(end - self.base).div_ceil(PAGE_SIZE_4K)]
+    }
+}
+
+/// A list of frames, linked through their [`Page`]s so it needs no memory of its own.
+///
+/// A frame can only be on one list at a time.
+pub struct FrameList {
+    head: usize,
+    len: usize,
+}
+
+impl Default for FrameList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameList {
+    pub const fn new() -> Self {
+        Self {
+            head: NO_FRAME,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Adds the frame at `paddr` to the front of the list.
+    ///
+    /// Panics if `pages` does not cover the frame.
+    pub fn push(&mut self, pages: &PageArray, paddr: PhysAddr) {
+        let page = pages.page(paddr).expect("frame without metadata");
+        page.next.store(self.head, Ordering::Relaxed);
+        self.head = paddr.as_usize();
+        self.len += 1;
+    }
+
+    /// Removes the frame at the front of the list.
+    pub fn pop(&mut self, pages: &PageArray) -> Option<PhysAddr> {
+        if self.head == NO_FRAME {
+            return None;
+        }
+        let paddr = PhysAddr::from(self.head);
+        let page = pages.page(paddr).expect("frame without metadata");
+        self.head = page.next.swap(NO_FRAME, Ordering::Relaxed);
+        self.len -= 1;
+        Some(paddr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: usize = 0x8000_0000;
+
+    fn array(frames: usize) -> PageArray {
+        let memory = Vec::leak((0..frames).map(|_| MaybeUninit::uninit()).collect());
+        PageArray::new(BASE, memory)
+    }
+
+    fn frame(index: usize) -> PhysAddr {
+        PhysAddr::from(BASE + index * PAGE_SIZE_4K)
+    }
+
+    #[test]
+    fn frames_start_reserved() {
+        let pages = array(4);
+        pages.free_range(frame(1).as_usize(), frame(3).as_usize());
+        assert_eq!(pages.counts(), (2, 2, 0));
+        assert_eq!(pages.page(frame(0)).unwrap().flags(), PageFlags::RESERVED);
+        assert!(pages.page(frame(4)).is_none());
+        assert!(pages.page(PhysAddr::from(BASE - 1)).is_none());
+    }
+
+    #[test]
+    fn last_reference_frees_the_frame() {
+        let pages = array(1);
+        pages.free_range(BASE, frame(1).as_usize());
+        let page = pages.page(frame(0)).unwrap();
+        page.allocated(PageFlags::USER);
+        page.set_owner(0x1234);
+        page.get();
+        assert_eq!(page.refcount(), 2);
+        assert!(!page.put());
+        assert!(page.put());
+        assert_eq!(page.refcount(), 0);
+        assert_eq!(page.flags(), PageFlags::empty());
+        assert_eq!(page.owner(), 0);
+    }
+
+    #[test]
+    fn reserved_frames_are_never_freed() {
+        let pages = array(1);
+        let page = pages.page(frame(0)).unwrap();
+        assert!(!page.put());
+        assert_eq!(page.refcount(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "free frame")]
+    fn putting_a_free_frame_panics() {
+        let pages = array(1);
+        pages.free_range(BASE, frame(1).as_usize());
+        pages.page(frame(0)).unwrap().put();
+    }
+
+    #[test]
+    fn mark_range_adds_flags() {
+        let pages = array(3);
+        pages.mark_range(
+            frame(1).as_usize() + 1,
+            frame(2).as_usize(),
+            PageFlags::KERNEL,
+        );
+        assert_eq!(pages.page(frame(0)).unwrap().flags(), PageFlags::RESERVED);
+        assert_eq!(
+            pages.page(frame(1)).unwrap().flags(),
+            PageFlags::RESERVED | PageFlags::KERNEL
+        );
+    }
+
+    #[test]
+    fn frame_list_is_last_in_first_out() {
+        let pages = array(3);
+        let mut list = FrameList::new();
+        for index in 0..3 {
+            list.push(&pages, frame(index));
+        }
+        assert_eq!(list.len(), 3);
+        let popped: Vec<_> = core::iter::from_fn(|| list.pop(&pages)).collect();
+        assert_eq!(popped, [frame(2), frame(1), frame(0)]);
+        assert!(list.is_empty());
+    }
+}
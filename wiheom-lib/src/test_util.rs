@@ -0,0 +1,22 @@
+/// Xorshift, good enough to shuffle allocations around.
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// A number in `0..bound`.
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+/// Leaks `size` bytes of zeroed memory aligned to `align` and returns its address.
+pub fn aligned_memory(size: usize, align: usize) -> usize {
+    let memory = Vec::leak(vec![0u8; size + align]);
+    (memory.as_mut_ptr() as usize).next_multiple_of(align)
+}